@@ -0,0 +1,484 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+// Sign and magnitude, the magnitude being little-endian base 2^32 limbs
+// without trailing zero limbs. Zero is always represented as non-negative.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u32>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    fn from_parts(negative: bool, mut magnitude: Vec<u32>) -> Self {
+        while magnitude.last() == Some(&0) {
+            magnitude.pop();
+        }
+        let negative = negative && !magnitude.is_empty();
+
+        Self {
+            negative,
+            magnitude,
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn bits(&self) -> u64 {
+        match self.magnitude.last() {
+            Some(top) => (self.magnitude.len() as u64 - 1) * 32 + (32 - top.leading_zeros() as u64),
+            None => 0,
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        if self.magnitude.len() > 2 {
+            return None;
+        }
+
+        let low = self.low_u64();
+        if self.negative {
+            match low.cmp(&(1 << 63)) {
+                Ordering::Less => Some(-(low as i64)),
+                Ordering::Equal => Some(i64::MIN),
+                Ordering::Greater => None,
+            }
+        } else {
+            i64::try_from(low).ok()
+        }
+    }
+
+    pub fn wrapping_to_i64(&self) -> i64 {
+        let low = self.low_u64() as i64;
+        if self.negative {
+            low.wrapping_neg()
+        } else {
+            low
+        }
+    }
+
+    pub fn saturating_to_i64(&self) -> i64 {
+        match self.to_i64() {
+            Some(n) => n,
+            None if self.negative => i64::MIN,
+            None => i64::MAX,
+        }
+    }
+
+    fn low_u64(&self) -> u64 {
+        let low = self.magnitude.first().copied().unwrap_or(0) as u64;
+        let high = self.magnitude.get(1).copied().unwrap_or(0) as u64;
+        (high << 32) | low
+    }
+
+    pub fn parse(input: &str) -> Option<Self> {
+        let (negative, digits) = match input.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, input.strip_prefix('+').unwrap_or(input)),
+        };
+
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+        for chunk in digits.as_bytes().chunks(9) {
+            let value: u32 = std::str::from_utf8(chunk).ok()?.parse().ok()?;
+            mul_small_add(&mut magnitude, 10u32.pow(chunk.len() as u32), value);
+        }
+
+        Some(Self::from_parts(negative, magnitude))
+    }
+
+    pub fn abs(&self) -> Self {
+        Self::from_parts(false, self.magnitude.clone())
+    }
+
+    /// Truncated division, like Rust's `/` and `%` on primitive integers.
+    /// Returns `None` when dividing by zero.
+    pub fn div_rem(&self, rhs: &BigInt) -> Option<(BigInt, BigInt)> {
+        if rhs.is_zero() {
+            return None;
+        }
+
+        let (quotient, remainder) = div_rem_magnitude(&self.magnitude, &rhs.magnitude);
+
+        Some((
+            Self::from_parts(self.negative != rhs.negative, quotient),
+            Self::from_parts(self.negative, remainder),
+        ))
+    }
+
+    pub fn pow(&self, mut exponent: u64) -> BigInt {
+        let mut base = self.clone();
+        let mut result = BigInt::from(1);
+
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+
+        result
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(n: i64) -> Self {
+        let magnitude = n.unsigned_abs();
+        Self::from_parts(n < 0, vec![magnitude as u32, (magnitude >> 32) as u32])
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.magnitude, &other.magnitude),
+            (true, true) => cmp_magnitude(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, rhs: &BigInt) -> BigInt {
+        if self.negative == rhs.negative {
+            return BigInt::from_parts(
+                self.negative,
+                add_magnitude(&self.magnitude, &rhs.magnitude),
+            );
+        }
+
+        match cmp_magnitude(&self.magnitude, &rhs.magnitude) {
+            Ordering::Less => {
+                BigInt::from_parts(rhs.negative, sub_magnitude(&rhs.magnitude, &self.magnitude))
+            }
+            _ => BigInt::from_parts(
+                self.negative,
+                sub_magnitude(&self.magnitude, &rhs.magnitude),
+            ),
+        }
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, rhs: &BigInt) -> BigInt {
+        self + &-rhs
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, rhs: &BigInt) -> BigInt {
+        let mut result = vec![0u32; self.magnitude.len() + rhs.magnitude.len()];
+
+        for (i, &a) in self.magnitude.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in rhs.magnitude.iter().enumerate() {
+                let t = a as u64 * b as u64 + result[i + j] as u64 + carry;
+                result[i + j] = t as u32;
+                carry = t >> 32;
+            }
+            result[i + rhs.magnitude.len()] = carry as u32;
+        }
+
+        BigInt::from_parts(self.negative != rhs.negative, result)
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt::from_parts(!self.negative, self.magnitude.clone())
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+
+        let mut chunks = vec![];
+        let mut magnitude = self.magnitude.clone();
+        while !magnitude.is_empty() {
+            chunks.push(div_small(&mut magnitude, 1_000_000_000));
+        }
+
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        if let Some(first) = chunks.next() {
+            write!(f, "{}", first)?;
+        }
+        for chunk in chunks {
+            write!(f, "{:09}", chunk)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn cmp_magnitude(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+
+    for (i, &x) in long.iter().enumerate() {
+        let t = x as u64 + short.get(i).copied().unwrap_or(0) as u64 + carry;
+        result.push(t as u32);
+        carry = t >> 32;
+    }
+    result.push(carry as u32);
+
+    result
+}
+
+// Requires a >= b.
+fn sub_magnitude(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+
+    for (i, &x) in a.iter().enumerate() {
+        let mut t = x as i64 - b.get(i).copied().unwrap_or(0) as i64 - borrow;
+        borrow = 0;
+        if t < 0 {
+            t += 1 << 32;
+            borrow = 1;
+        }
+        result.push(t as u32);
+    }
+
+    result
+}
+
+fn mul_small_add(magnitude: &mut Vec<u32>, factor: u32, addend: u32) {
+    let mut carry = addend as u64;
+
+    for limb in magnitude.iter_mut() {
+        let t = *limb as u64 * factor as u64 + carry;
+        *limb = t as u32;
+        carry = t >> 32;
+    }
+    if carry > 0 {
+        magnitude.push(carry as u32);
+    }
+}
+
+// Divides in place and returns the remainder.
+fn div_small(magnitude: &mut Vec<u32>, divisor: u32) -> u32 {
+    let mut remainder = 0u64;
+
+    for limb in magnitude.iter_mut().rev() {
+        let t = (remainder << 32) | *limb as u64;
+        *limb = (t / divisor as u64) as u32;
+        remainder = t % divisor as u64;
+    }
+    while magnitude.last() == Some(&0) {
+        magnitude.pop();
+    }
+
+    remainder as u32
+}
+
+fn div_rem_magnitude(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_magnitude(a, b) == Ordering::Less {
+        return (vec![], a.to_vec());
+    }
+
+    if b.len() == 1 {
+        let mut quotient = a.to_vec();
+        let remainder = div_small(&mut quotient, b[0]);
+        return (quotient, vec![remainder]);
+    }
+
+    // Knuth's algorithm D (TAOCP 4.3.1). Shifting both operands so that the
+    // divisor's top limb has its high bit set makes each estimated quotient
+    // limb at most two too large.
+    let shift = b[b.len() - 1].leading_zeros();
+    let shl = |x: &[u32]| {
+        let mut shifted = Vec::with_capacity(x.len() + 1);
+        let mut carry = 0;
+        for &limb in x {
+            let wide = (limb as u64) << shift;
+            shifted.push(wide as u32 | carry);
+            carry = (wide >> 32) as u32;
+        }
+        shifted.push(carry);
+        shifted
+    };
+
+    let mut v = shl(b);
+    v.pop();
+    let mut u = shl(a);
+    let n = v.len();
+    let (top, next) = (v[n - 1] as u64, v[n - 2] as u64);
+    let mut quotient = vec![0u32; a.len() - n + 1];
+
+    for j in (0..quotient.len()).rev() {
+        let numerator = (u[j + n] as u64) << 32 | u[j + n - 1] as u64;
+        let mut qhat = numerator / top;
+        let mut rhat = numerator % top;
+        while qhat >> 32 != 0 || qhat * next > (rhat << 32 | u[j + n - 2] as u64) {
+            qhat -= 1;
+            rhat += top;
+            if rhat >> 32 != 0 {
+                break;
+            }
+        }
+
+        let mut borrow = 0i64;
+        let mut carry = 0u64;
+        for i in 0..n {
+            let product = qhat * v[i] as u64 + carry;
+            carry = product >> 32;
+            let t = u[i + j] as i64 - borrow - (product & 0xffff_ffff) as i64;
+            u[i + j] = t as u32;
+            borrow = -(t >> 32);
+        }
+        let t = u[j + n] as i64 - borrow - carry as i64;
+        u[j + n] = t as u32;
+
+        // The estimate was one too large: add the divisor back.
+        if t < 0 {
+            qhat -= 1;
+            let mut carry = 0u64;
+            for i in 0..n {
+                let sum = u[i + j] as u64 + v[i] as u64 + carry;
+                u[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            u[j + n] = u[j + n].wrapping_add(carry as u32);
+        }
+        quotient[j] = qhat as u32;
+    }
+
+    let mut remainder: Vec<u32> = (0..n)
+        .map(|i| ((u[i] as u64 | (u[i + 1] as u64) << 32) >> shift) as u32)
+        .collect();
+    for limbs in [&mut quotient, &mut remainder] {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+    }
+
+    (quotient, remainder)
+}
+
+#[test]
+fn test_parse_and_display() {
+    for text in [
+        "0",
+        "-1",
+        "4294967296",
+        "18446744073709551616",
+        "-123456789012345678901234567890",
+    ] {
+        assert_eq!(BigInt::parse(text).unwrap().to_string(), text);
+    }
+
+    assert_eq!(BigInt::parse("-0").unwrap(), BigInt::zero());
+    assert_eq!(BigInt::parse("+007").unwrap().to_string(), "7");
+    assert!(BigInt::parse("12a").is_none());
+    assert!(BigInt::parse("-").is_none());
+}
+
+#[test]
+fn test_i64_conversions() {
+    for n in [0, 1, -1, i64::MAX, i64::MIN, 1 << 40, -(1 << 40)] {
+        assert_eq!(BigInt::from(n).to_i64(), Some(n));
+        assert_eq!(BigInt::from(n).to_string(), n.to_string());
+    }
+
+    let above = &BigInt::from(i64::MAX) + &BigInt::from(1);
+    let below = &BigInt::from(i64::MIN) - &BigInt::from(1);
+    assert_eq!(above.to_i64(), None);
+    assert_eq!(below.to_i64(), None);
+    assert_eq!(above.wrapping_to_i64(), i64::MIN);
+    assert_eq!(below.wrapping_to_i64(), i64::MAX);
+    assert_eq!(above.saturating_to_i64(), i64::MAX);
+    assert_eq!(below.saturating_to_i64(), i64::MIN);
+}
+
+#[test]
+fn test_arithmetic() {
+    let big = |s: &str| BigInt::parse(s).unwrap();
+
+    assert_eq!(
+        (&big("99999999999999999999") + &big("1")).to_string(),
+        "100000000000000000000"
+    );
+    assert_eq!((&big("5") - &big("12")).to_string(), "-7");
+    assert_eq!((&big("-5") + &big("5")), BigInt::zero());
+    assert_eq!(
+        (&big("123456789012345678901234567890") * &big("-987654321")).to_string(),
+        "-121932631124828532112482853211126352690"
+    );
+    assert_eq!(
+        big("2").pow(100).to_string(),
+        "1267650600228229401496703205376"
+    );
+
+    let (q, r) = big("1267650600228229401496703205377")
+        .div_rem(&big("-18446744073709551616"))
+        .unwrap();
+    assert_eq!(q.to_string(), "-68719476736");
+    assert_eq!(r.to_string(), "1");
+
+    let (q, r) = big("-7").div_rem(&big("2")).unwrap();
+    assert_eq!((q.to_string(), r.to_string()), ("-3".into(), "-1".into()));
+    assert!(big("1").div_rem(&BigInt::zero()).is_none());
+
+    // Operands that make the quotient estimate overshoot and need the
+    // add-back step, plus a large division checked against multiplication.
+    let (q, r) = big("340282366920938463463374607431768211455")
+        .div_rem(&big("18446744073709551617"))
+        .unwrap();
+    assert_eq!(
+        (q.to_string(), r.to_string()),
+        ("18446744073709551615".into(), "0".into())
+    );
+    let a = &big("3").pow(20000) + &big("12345");
+    let b = &big("7").pow(5000) - &big("1");
+    let (q, r) = a.div_rem(&b).unwrap();
+    assert_eq!(&(&q * &b) + &r, a);
+    assert!(r < b && !r.is_negative());
+
+    assert!(big("-100000000000000000000") < big("-1"));
+    assert!(big("100000000000000000000") > big("99999999999999999999"));
+}
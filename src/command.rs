@@ -1,10 +1,103 @@
 use std::fmt;
 
+use crate::bigint::BigInt;
+use crate::diagnostic::Diagnostic;
+use crate::tokenizer::escape;
+
 #[derive(Clone, PartialEq, Debug)]
 pub enum Value {
     Nothing,
     Int(i64),
+    BigInt(BigInt),
+    Bool(bool),
     String(String),
+    Error(Box<ErrorValue>),
+    Function(String),
+    Closure(usize),
+    Coroutine(usize),
+    Task(usize),
+    Channel(usize),
+}
+
+/// One entry of a backtrace: where a function was executing.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Frame {
+    pub pc: usize,
+    pub function: String,
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04} in {} at {}:{}",
+            self.pc, self.function, self.file, self.line
+        )
+    }
+}
+
+/// A runtime error caught by a `try` handler.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ErrorValue {
+    pub kind: String,
+    pub message: String,
+    pub file: String,
+    pub line: usize,
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} at {}:{}",
+            self.kind, self.message, self.file, self.line
+        )
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nothing => "void",
+            Value::Int(_) | Value::BigInt(_) => "int",
+            Value::Bool(_) => "bool",
+            Value::String(_) => "string",
+            Value::Error(_) => "error",
+            Value::Function(_) | Value::Closure(_) => "function",
+            Value::Coroutine(_) => "coroutine",
+            Value::Task(_) => "task",
+            Value::Channel(_) => "channel",
+        }
+    }
+
+    /// The process exit status for a result. Nonzero integers are clamped
+    /// to 1..=255 so the OS never truncates them to 0.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Value::Int(n) => clamp_exit(*n),
+            Value::BigInt(n) => clamp_exit(n.saturating_to_i64()),
+            Value::Bool(b) => !b as i32,
+            _ => 0,
+        }
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(n: BigInt) -> Self {
+        match n.to_i64() {
+            Some(n) => Value::Int(n),
+            None => Value::BigInt(n),
+        }
+    }
+}
+
+fn clamp_exit(n: i64) -> i32 {
+    match n {
+        0 => 0,
+        n => n.clamp(1, 255) as i32,
+    }
 }
 
 impl fmt::Display for Value {
@@ -12,7 +105,33 @@ impl fmt::Display for Value {
         match self {
             Value::Nothing => write!(f, "void"),
             Value::Int(n) => write!(f, "{}", n),
-            Value::String(s) => write!(f, "\"{}\"", s),
+            Value::BigInt(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::String(s) => write!(f, "\"{}\"", escape(s)),
+            Value::Error(e) => write!(f, "error({})", e),
+            Value::Function(name) => write!(f, "fn {}", name),
+            Value::Closure(handle) => write!(f, "closure #{}", handle),
+            Value::Coroutine(handle) => write!(f, "coroutine #{}", handle),
+            Value::Task(id) => write!(f, "task #{}", id),
+            Value::Channel(handle) => write!(f, "channel #{}", handle),
+        }
+    }
+}
+
+/// What integer arithmetic does when the exact result does not fit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Checked,
+    Wrapping,
+    Saturating,
+}
+
+impl Overflow {
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Overflow::Checked => "",
+            Overflow::Wrapping => ".wrap",
+            Overflow::Saturating => ".sat",
         }
     }
 }
@@ -21,19 +140,231 @@ impl fmt::Display for Value {
 pub enum Command {
     SetVar(String, Value),
     GetVar(String),
+    Store(String),
+    Load(String),
     Push(Value),
     Pop,
-    Add,
-    Sub,
-    Mul,
-    Div,
+    Add(Overflow),
+    Sub(Overflow),
+    Mul(Overflow),
+    Div(Overflow),
+    Mod(Overflow),
+    Pow(Overflow),
+    Neg(Overflow),
+    Abs(Overflow),
     FuncCall(String),
+    TailCall(String),
+    PushFn(String),
+    ICall,
+    Closure(String, usize),
+    GetCap(usize),
+    SetCap(usize),
+    Coroutine,
+    Resume,
+    Yield,
+    Status,
+    Spawn,
+    Join,
+    Channel,
+    Send,
+    Recv,
     Ret,
     End,
+    Halt,
+    Exit(Option<i64>),
     Cmp,
     Jn(String),
     Jp(String),
     Jz(String),
+    Dup,
+    Swap,
+    Over,
+    Rot,
+    Drop,
+    Pick(usize),
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Not,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Jmp(String),
+    Jt(String),
+    Jf(String),
+    Try(String),
+    EndTry,
+    Throw,
+    ErrKind,
+    ErrMsg,
+    ErrLine,
+}
+
+/// How many operand stack slots a command needs on entry and how many it
+/// leaves in their place, in the spirit of Forth's `( a b -- b a )` notation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    pub const fn new(inputs: usize, outputs: usize) -> Self {
+        Self { inputs, outputs }
+    }
+}
+
+impl Command {
+    /// The static stack effect of the command, or `None` when it depends on
+    /// the callee (function calls).
+    pub fn stack_effect(&self) -> Option<StackEffect> {
+        let effect = match self {
+            Command::SetVar(_, _) | Command::GetVar(_) => StackEffect::new(0, 0),
+            Command::Store(_) => StackEffect::new(1, 0),
+            Command::Load(_) => StackEffect::new(0, 1),
+            Command::Push(_) => StackEffect::new(0, 1),
+            Command::Pop | Command::Drop => StackEffect::new(1, 0),
+            Command::Add(_)
+            | Command::Sub(_)
+            | Command::Mul(_)
+            | Command::Div(_)
+            | Command::Mod(_)
+            | Command::Pow(_)
+            | Command::Cmp => StackEffect::new(2, 1),
+            Command::Neg(_) | Command::Abs(_) => StackEffect::new(1, 1),
+            Command::FuncCall(_) | Command::TailCall(_) | Command::ICall => return None,
+            Command::PushFn(_) => StackEffect::new(0, 1),
+            Command::Closure(_, n) => StackEffect::new(*n, 1),
+            Command::GetCap(_) => StackEffect::new(0, 1),
+            Command::SetCap(_) => StackEffect::new(1, 0),
+            Command::Coroutine | Command::Status => StackEffect::new(1, 1),
+            Command::Resume | Command::Yield => return None,
+            Command::Spawn => StackEffect::new(2, 1),
+            Command::Send => StackEffect::new(2, 0),
+            Command::Join | Command::Channel | Command::Recv => StackEffect::new(1, 1),
+            Command::Ret | Command::End | Command::Halt => StackEffect::new(0, 0),
+            Command::Exit(Some(_)) => StackEffect::new(0, 0),
+            Command::Exit(None) => StackEffect::new(1, 0),
+            Command::Jn(_) | Command::Jp(_) | Command::Jz(_) => StackEffect::new(1, 0),
+            Command::Dup => StackEffect::new(1, 2),
+            Command::Swap => StackEffect::new(2, 2),
+            Command::Over => StackEffect::new(2, 3),
+            Command::Rot => StackEffect::new(3, 3),
+            Command::Pick(n) => StackEffect::new(n + 1, n + 2),
+            Command::Eq
+            | Command::Ne
+            | Command::Lt
+            | Command::Le
+            | Command::Gt
+            | Command::Ge
+            | Command::And
+            | Command::Or
+            | Command::Xor
+            | Command::Shl
+            | Command::Shr => StackEffect::new(2, 1),
+            Command::Not => StackEffect::new(1, 1),
+            Command::Jmp(_) => StackEffect::new(0, 0),
+            Command::Jt(_) | Command::Jf(_) => StackEffect::new(1, 0),
+            Command::Try(_) | Command::EndTry => StackEffect::new(0, 0),
+            Command::Throw => StackEffect::new(1, 0),
+            Command::ErrKind | Command::ErrMsg | Command::ErrLine => StackEffect::new(1, 1),
+        };
+
+        Some(effect)
+    }
+
+    pub fn label_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Command::Jn(label)
+            | Command::Jp(label)
+            | Command::Jz(label)
+            | Command::Jmp(label)
+            | Command::Jt(label)
+            | Command::Jf(label)
+            | Command::Try(label) => Some(label),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::SetVar(name, Value::BigInt(n)) => write!(f, "set {} {}n", name, n),
+            Command::SetVar(name, value) => write!(f, "set {} {}", name, value),
+            Command::GetVar(name) => write!(f, "get {}", name),
+            Command::Store(name) => write!(f, "store {}", name),
+            Command::Load(name) => write!(f, "load {}", name),
+            Command::Push(Value::BigInt(n)) => write!(f, "push {}n", n),
+            Command::Push(value) => write!(f, "push {}", value),
+            Command::Pop => write!(f, "pop"),
+            Command::Add(mode) => write!(f, "add{}", mode.suffix()),
+            Command::Sub(mode) => write!(f, "sub{}", mode.suffix()),
+            Command::Mul(mode) => write!(f, "mul{}", mode.suffix()),
+            Command::Div(mode) => write!(f, "div{}", mode.suffix()),
+            Command::Mod(mode) => write!(f, "mod{}", mode.suffix()),
+            Command::Pow(mode) => write!(f, "pow{}", mode.suffix()),
+            Command::Neg(mode) => write!(f, "neg{}", mode.suffix()),
+            Command::Abs(mode) => write!(f, "abs{}", mode.suffix()),
+            Command::FuncCall(name) => write!(f, "call {}", name),
+            Command::TailCall(name) => write!(f, "tailcall {}", name),
+            Command::PushFn(name) => write!(f, "pushfn {}", name),
+            Command::ICall => write!(f, "icall"),
+            Command::Closure(name, n) => write!(f, "closure {} {}", name, n),
+            Command::GetCap(slot) => write!(f, "getcap {}", slot),
+            Command::SetCap(slot) => write!(f, "setcap {}", slot),
+            Command::Coroutine => write!(f, "coroutine"),
+            Command::Resume => write!(f, "resume"),
+            Command::Yield => write!(f, "yield"),
+            Command::Status => write!(f, "status"),
+            Command::Spawn => write!(f, "spawn"),
+            Command::Join => write!(f, "join"),
+            Command::Channel => write!(f, "channel"),
+            Command::Send => write!(f, "send"),
+            Command::Recv => write!(f, "recv"),
+            Command::Ret => write!(f, "ret"),
+            Command::End => write!(f, "end"),
+            Command::Halt => write!(f, "halt"),
+            Command::Exit(Some(code)) => write!(f, "exit {}", code),
+            Command::Exit(None) => write!(f, "exit"),
+            Command::Cmp => write!(f, "cmp"),
+            Command::Jn(label) => write!(f, "jn {}", label),
+            Command::Jp(label) => write!(f, "jp {}", label),
+            Command::Jz(label) => write!(f, "jz {}", label),
+            Command::Dup => write!(f, "dup"),
+            Command::Swap => write!(f, "swap"),
+            Command::Over => write!(f, "over"),
+            Command::Rot => write!(f, "rot"),
+            Command::Drop => write!(f, "drop"),
+            Command::Pick(n) => write!(f, "pick {}", n),
+            Command::Eq => write!(f, "eq"),
+            Command::Ne => write!(f, "ne"),
+            Command::Lt => write!(f, "lt"),
+            Command::Le => write!(f, "le"),
+            Command::Gt => write!(f, "gt"),
+            Command::Ge => write!(f, "ge"),
+            Command::Not => write!(f, "not"),
+            Command::And => write!(f, "and"),
+            Command::Or => write!(f, "or"),
+            Command::Xor => write!(f, "xor"),
+            Command::Shl => write!(f, "shl"),
+            Command::Shr => write!(f, "shr"),
+            Command::Jmp(label) => write!(f, "jmp {}", label),
+            Command::Jt(label) => write!(f, "jt {}", label),
+            Command::Jf(label) => write!(f, "jf {}", label),
+            Command::Try(label) => write!(f, "try {}", label),
+            Command::EndTry => write!(f, "endtry"),
+            Command::Throw => write!(f, "throw"),
+            Command::ErrKind => write!(f, "errkind"),
+            Command::ErrMsg => write!(f, "errmsg"),
+            Command::ErrLine => write!(f, "errline"),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -43,4 +374,165 @@ pub enum EngineError {
     MismatchType,
     UnknownCommand(String),
     EmptyStack,
+    UnterminatedString,
+    InvalidEscape(String),
+    UnknownDirective(String),
+    UnknownSymbol(String),
+    UnknownLabel(String),
+    DuplicateDefinition(String),
+    InvalidShift(i64),
+    DivisionByZero,
+    Overflow,
+    NegativeExponent,
+    Include(String),
+    Io(String),
+    Output(String),
+    Usage(String),
+    Snapshot(String),
+    Store(String),
+    Diverged(String),
+    Syntax(Vec<Diagnostic>),
+    NoHandler,
+    StackOverflow,
+    TailCall(String),
+    NoCapture(usize),
+    NotResumable(&'static str),
+    NotInCoroutine,
+    InvalidCapacity(i64),
+    Deadlock,
+    Interrupted { pc: usize, backtrace: Vec<Frame> },
+    Timeout { pc: usize, backtrace: Vec<Frame> },
+    Paused { pc: usize, watch: usize },
+    Thrown(Box<ErrorValue>),
+}
+
+impl EngineError {
+    /// The name an error is reported under when it is caught as a value.
+    pub fn kind(&self) -> &str {
+        match self {
+            EngineError::MissingVariable(_) => "missing_variable",
+            EngineError::MismatchNumParams => "wrong_operands",
+            EngineError::MismatchType => "type_mismatch",
+            EngineError::UnknownCommand(_) => "unknown_command",
+            EngineError::EmptyStack => "empty_stack",
+            EngineError::UnterminatedString | EngineError::InvalidEscape(_) => "bad_string",
+            EngineError::UnknownDirective(_) => "unknown_directive",
+            EngineError::UnknownSymbol(_) => "unknown_symbol",
+            EngineError::UnknownLabel(_) => "unknown_label",
+            EngineError::DuplicateDefinition(_) => "duplicate_definition",
+            EngineError::InvalidShift(_) => "invalid_shift",
+            EngineError::DivisionByZero => "division_by_zero",
+            EngineError::Overflow => "overflow",
+            EngineError::NegativeExponent => "negative_exponent",
+            EngineError::Include(_) => "include",
+            EngineError::Io(_) => "io",
+            EngineError::Output(_) => "output",
+            EngineError::Usage(_) => "usage",
+            EngineError::Snapshot(_) => "snapshot",
+            EngineError::Store(_) => "store",
+            EngineError::Diverged(_) => "diverged",
+            EngineError::Syntax(_) => "syntax",
+            EngineError::NoHandler => "no_handler",
+            EngineError::StackOverflow => "stack_overflow",
+            EngineError::TailCall(_) => "tail_call",
+            EngineError::NoCapture(_) => "no_capture",
+            EngineError::NotResumable(_) => "not_resumable",
+            EngineError::NotInCoroutine => "not_in_coroutine",
+            EngineError::InvalidCapacity(_) => "invalid_capacity",
+            EngineError::Deadlock => "deadlock",
+            EngineError::Interrupted { .. } => "interrupted",
+            EngineError::Timeout { .. } => "timeout",
+            EngineError::Paused { .. } => "paused",
+            EngineError::Thrown(e) => &e.kind,
+        }
+    }
+
+    /// Resource limits (and anything raised before the program runs) always
+    /// abort; everything else can be handled by `try`.
+    pub fn is_catchable(&self) -> bool {
+        !matches!(
+            self,
+            EngineError::Overflow
+                | EngineError::StackOverflow
+                | EngineError::Deadlock
+                | EngineError::Interrupted { .. }
+                | EngineError::Timeout { .. }
+                | EngineError::Paused { .. }
+                | EngineError::Include(_)
+                | EngineError::Io(_)
+                | EngineError::Usage(_)
+                | EngineError::Snapshot(_)
+                | EngineError::Store(_)
+                | EngineError::Diverged(_)
+                | EngineError::Syntax(_)
+        )
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::MissingVariable(name) => write!(f, "missing variable `{}`", name),
+            EngineError::MismatchNumParams => write!(f, "wrong number of operands"),
+            EngineError::MismatchType => write!(f, "type mismatch"),
+            EngineError::UnknownCommand(name) => write!(f, "unknown command `{}`", name),
+            EngineError::EmptyStack => write!(f, "empty stack"),
+            EngineError::UnterminatedString => write!(f, "unterminated string literal"),
+            EngineError::InvalidEscape(escape) => write!(f, "invalid escape `{}`", escape),
+            EngineError::UnknownDirective(name) => write!(f, "unknown directive `{}`", name),
+            EngineError::UnknownSymbol(name) => write!(f, "unknown symbol `{}`", name),
+            EngineError::UnknownLabel(name) => write!(f, "unknown label `{}`", name),
+            EngineError::DuplicateDefinition(what) => write!(f, "duplicate definition of {}", what),
+            EngineError::DivisionByZero => write!(f, "division by zero"),
+            EngineError::Overflow => write!(f, "integer overflow"),
+            EngineError::NegativeExponent => write!(f, "negative exponent"),
+            EngineError::InvalidShift(amount) => write!(f, "invalid shift amount {}", amount),
+            EngineError::Include(message) => write!(f, "cannot include {}", message),
+            EngineError::Io(message) => write!(f, "cannot read {}", message),
+            EngineError::Output(message) => write!(f, "cannot write output: {}", message),
+            EngineError::Usage(message) => write!(f, "usage: {}", message),
+            EngineError::Snapshot(message) => write!(f, "{}", message),
+            EngineError::Store(message) => write!(f, "persistent store {}", message),
+            EngineError::Diverged(message) => write!(f, "replay diverged: {}", message),
+            EngineError::NoHandler => write!(f, "`endtry` without an active `try`"),
+            EngineError::StackOverflow => write!(f, "call stack overflow"),
+            EngineError::TailCall(name) => write!(
+                f,
+                "cannot tail call `{}` while a `try` in the current function is active",
+                name
+            ),
+            EngineError::Thrown(e) => write!(f, "uncaught {}", e),
+            EngineError::NoCapture(slot) => write!(f, "no captured slot {}", slot),
+            EngineError::NotResumable(status) => {
+                write!(f, "cannot resume a {} coroutine", status)
+            }
+            EngineError::NotInCoroutine => write!(f, "`yield` outside of a coroutine"),
+            EngineError::InvalidCapacity(n) => write!(f, "invalid channel capacity {}", n),
+            EngineError::Deadlock => write!(f, "deadlock: every task is blocked"),
+            EngineError::Interrupted { pc, backtrace } => {
+                write!(f, "interrupted at {:04}", pc)?;
+                backtrace
+                    .iter()
+                    .try_for_each(|frame| write!(f, "\n    {}", frame))
+            }
+            EngineError::Paused { pc, watch } => {
+                write!(f, "paused by watchpoint {} at {:04}", watch, pc)
+            }
+            EngineError::Timeout { pc, backtrace } => {
+                write!(f, "timed out at {:04}", pc)?;
+                backtrace
+                    .iter()
+                    .try_for_each(|frame| write!(f, "\n    {}", frame))
+            }
+            EngineError::Syntax(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{}\n", diagnostic)?;
+                }
+                match diagnostics.len() {
+                    1 => write!(f, "aborting due to previous error"),
+                    n => write!(f, "aborting due to {} previous errors", n),
+                }
+            }
+        }
+    }
 }
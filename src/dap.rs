@@ -0,0 +1,647 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+
+use crate::command::{EngineError, Value};
+use crate::debugger::{Debugger, Stop};
+use crate::eval::{Evaluator, InterruptHandle};
+use crate::json::Json;
+use crate::parser::{Parser, Program};
+use crate::pool::Buffer;
+
+// The evaluator runs one task at a time, so clients see a single thread.
+const THREAD: usize = 1;
+
+// `variablesReference`s. Locals are numbered from LOCALS by frame.
+const STACK: usize = 1;
+const GLOBALS: usize = 2;
+const LOCALS: usize = 1000;
+
+type Requests = Receiver<Result<Json, EngineError>>;
+
+fn read_message(input: &mut impl BufRead) -> Result<Option<Json>, EngineError> {
+    let error = |e: std::io::Error| EngineError::Io(format!("debug adapter input: {}", e));
+
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).map_err(error)? == 0 {
+            return Ok(None);
+        }
+        match header.trim_end() {
+            "" if length.is_some() => break,
+            "" => {}
+            header => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("Content-Length") {
+                        length = value.trim().parse().ok();
+                    }
+                }
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body).map_err(error)?;
+    let body = String::from_utf8_lossy(&body);
+    Json::parse(&body)
+        .map(Some)
+        .map_err(|e| EngineError::Io(format!("debug adapter input: {}", e)))
+}
+
+// Requests are read on their own thread so that `pause` can interrupt a
+// program that is running.
+fn spawn_reader(mut input: impl BufRead + Send + 'static, interrupt: InterruptHandle) -> Requests {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || loop {
+        let message = read_message(&mut input).transpose();
+        let Some(message) = message else {
+            break;
+        };
+        if let Ok(request) = &message {
+            if command(request) == "pause" {
+                interrupt.interrupt();
+            }
+        }
+        let failed = message.is_err();
+        if sender.send(message).is_err() || failed {
+            break;
+        }
+    });
+    receiver
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").as_str().unwrap_or("")
+}
+
+struct Client<W> {
+    out: W,
+    seq: usize,
+}
+
+impl<W: Write> Client<W> {
+    fn send(&mut self, kind: &str, members: Vec<(String, Json)>) -> Result<(), EngineError> {
+        self.seq += 1;
+        let mut message = vec![
+            ("seq".into(), self.seq.into()),
+            ("type".into(), kind.into()),
+        ];
+        message.extend(
+            members
+                .into_iter()
+                .filter(|(_, value)| *value != Json::Null),
+        );
+        let message = Json::Object(message).to_string();
+
+        write!(
+            self.out,
+            "Content-Length: {}\r\n\r\n{}",
+            message.len(),
+            message
+        )
+        .and_then(|_| self.out.flush())
+        .map_err(|e| EngineError::Output(e.to_string()))
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) -> Result<(), EngineError> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq".into(), request.get("seq").clone()),
+                ("success".into(), true.into()),
+                ("command".into(), command(request).into()),
+                ("body".into(), body),
+            ],
+        )
+    }
+
+    fn fail(&mut self, request: &Json, message: &str) -> Result<(), EngineError> {
+        self.send(
+            "response",
+            vec![
+                ("request_seq".into(), request.get("seq").clone()),
+                ("success".into(), false.into()),
+                ("command".into(), command(request).into()),
+                ("message".into(), message.into()),
+            ],
+        )
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> Result<(), EngineError> {
+        self.send(
+            "event",
+            vec![("event".into(), event.into()), ("body".into(), body)],
+        )
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsEvaluateForHovers", true.into()),
+        ("supportsStepBack", true.into()),
+        ("supportsTerminateRequest", true.into()),
+    ])
+}
+
+fn launch(arguments: &Json) -> Result<Program, EngineError> {
+    let path = arguments
+        .get("program")
+        .as_str()
+        .ok_or_else(|| EngineError::Usage("launch needs a `program`".into()))?;
+    let program = Parser::new().parse_file(path)?;
+    if !program.functions.contains_key("main") {
+        return Err(EngineError::UnknownSymbol(format!("main in {}", path)));
+    }
+    Ok(program)
+}
+
+fn canonical(path: &str) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.into())
+}
+
+fn variable(name: String, value: &Value) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("value", value.to_string().into()),
+        ("type", value.type_name().into()),
+        ("variablesReference", 0.into()),
+    ])
+}
+
+/// Serves one debugging session over the Debug Adapter Protocol, reading
+/// requests from `input` and writing responses and events to `out`.
+/// Returns the exit code of the debugged program.
+pub fn serve(input: impl BufRead + Send + 'static, out: impl Write) -> Result<i32, EngineError> {
+    let interrupt = InterruptHandle::default();
+    let requests = spawn_reader(input, interrupt.clone());
+    let mut client = Client { out, seq: 0 };
+
+    let (program, stop_on_entry) = loop {
+        let Ok(request) = requests.recv() else {
+            return Ok(0);
+        };
+        let request = request?;
+        match command(&request) {
+            "initialize" => client.respond(&request, capabilities())?,
+            "launch" => {
+                let arguments = request.get("arguments");
+                match launch(arguments) {
+                    Ok(program) => {
+                        client.respond(&request, Json::Null)?;
+                        let stop_on_entry = arguments.get("stopOnEntry").as_bool();
+                        break (program, stop_on_entry.unwrap_or(false));
+                    }
+                    Err(e) => client.fail(&request, &e.to_string())?,
+                }
+            }
+            "disconnect" | "terminate" => {
+                client.respond(&request, Json::Null)?;
+                return Ok(0);
+            }
+            _ => client.fail(&request, "no program has been launched")?,
+        }
+    };
+    client.event("initialized", Json::Null)?;
+
+    let output = Buffer::default();
+    let mut evaluator = Evaluator::new();
+    evaluator.set_output(output.clone());
+    evaluator.set_interrupt_handle(interrupt);
+
+    Session {
+        debugger: Debugger::new(&program, evaluator)?,
+        output,
+        client,
+        exception: false,
+        exit_code: 0,
+    }
+    .run(requests, stop_on_entry)
+}
+
+enum Stride {
+    In,
+    Over,
+    Out,
+}
+
+struct Session<'p, W> {
+    debugger: Debugger<'p>,
+    output: Buffer,
+    client: Client<W>,
+    // Whether the error the program stopped on has been reported. The next
+    // attempt to run on ends the session.
+    exception: bool,
+    exit_code: i32,
+}
+
+impl<W: Write> Session<'_, W> {
+    fn run(mut self, requests: Requests, stop_on_entry: bool) -> Result<i32, EngineError> {
+        while let Ok(request) = requests.recv() {
+            let request = request?;
+            let arguments = request.get("arguments");
+
+            match command(&request) {
+                "setBreakpoints" => {
+                    let body = self.set_breakpoints(arguments);
+                    self.client.respond(&request, body)?;
+                }
+                "configurationDone" => {
+                    self.client.respond(&request, Json::Null)?;
+                    match stop_on_entry {
+                        true => self.stopped("entry", None)?,
+                        false => {
+                            let stop = self.debugger.resume();
+                            self.report(stop)?;
+                        }
+                    }
+                }
+                "threads" => {
+                    let thread = Json::object([("id", THREAD.into()), ("name", "main".into())]);
+                    let body = Json::object([("threads", vec![thread].into())]);
+                    self.client.respond(&request, body)?;
+                }
+                "stackTrace" => {
+                    let body = self.stack_trace();
+                    self.client.respond(&request, body)?;
+                }
+                "scopes" => {
+                    let frame = arguments.get("frameId").as_usize().unwrap_or(0);
+                    let body = self.scopes(frame);
+                    self.client.respond(&request, body)?;
+                }
+                "variables" => {
+                    let reference = arguments.get("variablesReference").as_usize();
+                    let body = self.variables(reference.unwrap_or(0));
+                    self.client.respond(&request, body)?;
+                }
+                "evaluate" => {
+                    let expression = arguments.get("expression").as_str().unwrap_or("").trim();
+                    match self.debugger.evaluator().vars().get(expression) {
+                        Some(value) => {
+                            let body = Json::object([
+                                ("result", value.to_string().into()),
+                                ("type", value.type_name().into()),
+                                ("variablesReference", 0.into()),
+                            ]);
+                            self.client.respond(&request, body)?;
+                        }
+                        None => {
+                            let message = format!("`{}` is not a variable", expression);
+                            self.client.fail(&request, &message)?;
+                        }
+                    }
+                }
+                "continue" | "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => {
+                    let body = Json::object([("allThreadsContinued", true.into())]);
+                    self.client.respond(&request, body)?;
+
+                    let stop = match command(&request) {
+                        "continue" => self.debugger.resume(),
+                        "next" => self.step(Stride::Over),
+                        "stepIn" => self.step(Stride::In),
+                        "stepOut" => self.step(Stride::Out),
+                        "stepBack" => self.debugger.reverse_step(),
+                        _ => self.debugger.reverse_resume(),
+                    };
+                    if matches!(command(&request), "stepBack" | "reverseContinue") {
+                        self.exception = false;
+                    }
+                    self.report(stop)?;
+                }
+                "pause" => {
+                    // A running program has been interrupted by the reader
+                    // already; a stopped one has nothing to pause.
+                    self.debugger.evaluator().interrupt_handle().clear();
+                    self.client.respond(&request, Json::Null)?;
+                }
+                "disconnect" | "terminate" => {
+                    self.client.respond(&request, Json::Null)?;
+                    break;
+                }
+                "initialize" | "launch" => {
+                    self.client
+                        .fail(&request, "a program has already been launched")?;
+                }
+                name => {
+                    let message = format!("unsupported request `{}`", name);
+                    self.client.fail(&request, &message)?;
+                }
+            }
+        }
+
+        Ok(self.exit_code)
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Json {
+        let program = self.debugger.program();
+        let path = arguments.get("source").get("path").as_str().unwrap_or("");
+        let wanted = canonical(path);
+
+        let mut files: Vec<&str> = program.locations.iter().map(|l| l.file.as_str()).collect();
+        files.sort();
+        files.dedup();
+        let file = files.into_iter().find(|file| canonical(file) == wanted);
+
+        let previous: Vec<_> = self
+            .debugger
+            .breakpoints()
+            .filter(|&pc| Some(program.location(pc).file.as_str()) == file)
+            .collect();
+        for pc in previous {
+            self.debugger.remove_breakpoint(pc);
+        }
+
+        let breakpoints = arguments
+            .get("breakpoints")
+            .as_array()
+            .iter()
+            .map(|breakpoint| {
+                let line = breakpoint.get("line").as_usize().unwrap_or(0);
+                match file.and_then(|file| program.pc_at(file, line)) {
+                    Some(pc) => {
+                        self.debugger.add_breakpoint(pc);
+                        Json::object([
+                            ("id", pc.into()),
+                            ("verified", true.into()),
+                            ("line", line.into()),
+                        ])
+                    }
+                    None => Json::object([
+                        ("verified", false.into()),
+                        ("line", line.into()),
+                        ("message", "no instruction on this line".into()),
+                    ]),
+                }
+            });
+        let breakpoints: Vec<_> = breakpoints.collect();
+        Json::object([("breakpoints", breakpoints.into())])
+    }
+
+    fn stack_trace(&self) -> Json {
+        let backtrace = self.debugger.evaluator().backtrace(self.debugger.program());
+        let frames: Vec<_> = backtrace
+            .iter()
+            .enumerate()
+            .map(|(id, frame)| {
+                let name = frame.file.rsplit(['/', '\\']).next().unwrap_or(&frame.file);
+                let source = Json::object([
+                    ("name", name.into()),
+                    ("path", canonical(&frame.file).display().to_string().into()),
+                ]);
+                Json::object([
+                    ("id", id.into()),
+                    ("name", frame.function.as_str().into()),
+                    ("source", source),
+                    ("line", frame.line.into()),
+                    ("column", 1.into()),
+                ])
+            })
+            .collect();
+
+        Json::object([
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", frames.into()),
+        ])
+    }
+
+    fn scopes(&self, frame: usize) -> Json {
+        let scope = |name: &str, reference: usize| {
+            Json::object([
+                ("name", name.into()),
+                ("variablesReference", reference.into()),
+                ("expensive", false.into()),
+            ])
+        };
+        let scopes = vec![
+            scope("Locals", LOCALS + frame),
+            scope("Stack", STACK),
+            scope("Globals", GLOBALS),
+        ];
+        Json::object([("scopes", scopes.into())])
+    }
+
+    fn variables(&self, reference: usize) -> Json {
+        let evaluator = self.debugger.evaluator();
+        let variables: Vec<_> = match reference {
+            STACK => evaluator
+                .stack()
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(depth, value)| variable(format!("[{}]", depth), value))
+                .collect(),
+            GLOBALS => {
+                let mut vars: Vec<_> = evaluator.vars().iter().collect();
+                vars.sort_by(|a, b| a.0.cmp(b.0));
+                vars.into_iter()
+                    .map(|(name, value)| variable(name.clone(), value))
+                    .collect()
+            }
+            reference if reference >= LOCALS => evaluator
+                .captures(reference - LOCALS)
+                .iter()
+                .enumerate()
+                .map(|(slot, value)| variable(format!("capture {}", slot), value))
+                .collect(),
+            _ => vec![],
+        };
+        Json::object([("variables", variables.into())])
+    }
+
+    // Runs until execution reaches another line. Stepping over waits for
+    // the line in the same call or its caller, stepping out for the caller.
+    fn step(&mut self, stride: Stride) -> Stop {
+        let program = self.debugger.program();
+        let depth = self.debugger.evaluator().call_depth();
+        let start = program.location(self.debugger.evaluator().pc());
+
+        loop {
+            let stop = self.debugger.step();
+            if stop != Stop::Step {
+                return stop;
+            }
+
+            let evaluator = self.debugger.evaluator();
+            let pc = evaluator.pc();
+            if self
+                .debugger
+                .breakpoints()
+                .any(|breakpoint| breakpoint == pc)
+            {
+                return Stop::Breakpoint(pc);
+            }
+            let now = evaluator.call_depth();
+            let moved = program.location(pc) != start;
+            let done = match stride {
+                Stride::In => now != depth || moved,
+                Stride::Over => now < depth || (now == depth && moved),
+                Stride::Out => now < depth,
+            };
+            if done {
+                return Stop::Step;
+            }
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> Result<(), EngineError> {
+        let mut body = vec![
+            ("reason".into(), reason.into()),
+            ("threadId".into(), THREAD.into()),
+            ("allThreadsStopped".into(), true.into()),
+        ];
+        if let Some(text) = text {
+            body.push(("text".into(), text.into()));
+        }
+        self.client.event("stopped", Json::Object(body))
+    }
+
+    fn report(&mut self, stop: Stop) -> Result<(), EngineError> {
+        let printed = self.output.take();
+        if !printed.is_empty() {
+            let output = String::from_utf8_lossy(&printed).into_owned();
+            let body = Json::object([("category", "stdout".into()), ("output", output.into())]);
+            self.client.event("output", body)?;
+        }
+
+        match stop {
+            Stop::Step | Stop::Start => self.stopped("step", None),
+            Stop::Breakpoint(_) => self.stopped("breakpoint", None),
+            Stop::Watchpoint(_) => self.stopped("data breakpoint", None),
+            Stop::Interrupted => self.stopped("pause", None),
+            Stop::Failed(message) if !self.exception => {
+                self.exception = true;
+                let body = Json::object([
+                    ("category", "stderr".into()),
+                    ("output", format!("error: {}\n", message).into()),
+                ]);
+                self.client.event("output", body)?;
+                self.stopped("exception", Some(message))
+            }
+            Stop::Failed(_) => self.exit(1),
+            Stop::Finished(value) => self.exit(value.exit_code()),
+        }
+    }
+
+    fn exit(&mut self, code: i32) -> Result<(), EngineError> {
+        self.exit_code = code;
+        self.client
+            .event("exited", Json::object([("exitCode", code.into())]))?;
+        self.client.event("terminated", Json::Null)
+    }
+}
+
+#[test]
+fn test_scripted_session() -> Result<(), EngineError> {
+    let requests = [
+        r#"{"command":"initialize","arguments":{"adapterID":"onehour"}}"#,
+        r#"{"command":"launch","arguments":{"program":"samples/debug.onehour"}}"#,
+        r#"{"command":"setBreakpoints","arguments":{"source":{"path":"samples/debug.onehour"},"breakpoints":[{"line":11},{"line":6}]}}"#,
+        r#"{"command":"configurationDone"}"#,
+        r#"{"command":"threads"}"#,
+        r#"{"command":"stepIn","arguments":{"threadId":1}}"#,
+        r#"{"command":"stackTrace","arguments":{"threadId":1}}"#,
+        r#"{"command":"scopes","arguments":{"frameId":0}}"#,
+        r#"{"command":"variables","arguments":{"variablesReference":1}}"#,
+        r#"{"command":"evaluate","arguments":{"expression":"x","context":"hover"}}"#,
+        r#"{"command":"evaluate","arguments":{"expression":"y","context":"hover"}}"#,
+        r#"{"command":"stepOut","arguments":{"threadId":1}}"#,
+        r#"{"command":"stepBack","arguments":{"threadId":1}}"#,
+        r#"{"command":"next","arguments":{"threadId":1}}"#,
+        r#"{"command":"continue","arguments":{"threadId":1}}"#,
+        r#"{"command":"disconnect"}"#,
+    ];
+    let mut input = String::new();
+    for (seq, request) in requests.iter().enumerate() {
+        let request = format!(r#"{{"seq":{},"type":"request",{}"#, seq + 1, &request[1..]);
+        input.push_str(&format!(
+            "Content-Length: {}\r\n\r\n{}",
+            request.len(),
+            request
+        ));
+    }
+
+    let mut out = vec![];
+    let code = serve(std::io::Cursor::new(input.into_bytes()), &mut out)?;
+    assert_eq!(code, 9);
+
+    let mut out = out.as_slice();
+    let mut messages = vec![];
+    while let Some(message) = read_message(&mut out)? {
+        messages.push(message);
+    }
+
+    let summary: Vec<_> = messages
+        .iter()
+        .map(|message| match message.get("type").as_str() {
+            Some("event") => message.get("event").as_str().unwrap().to_string(),
+            _ => format!(
+                "{} {}",
+                command(message),
+                message.get("success").as_bool().unwrap()
+            ),
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            "initialize true",
+            "launch true",
+            "initialized",
+            "setBreakpoints true",
+            "configurationDone true",
+            "stopped",
+            "threads true",
+            "stepIn true",
+            "stopped",
+            "stackTrace true",
+            "scopes true",
+            "variables true",
+            "evaluate true",
+            "evaluate false",
+            "stepOut true",
+            "stopped",
+            "stepBack true",
+            "stopped",
+            "next true",
+            "stopped",
+            "continue true",
+            "output",
+            "exited",
+            "terminated",
+            "disconnect true",
+        ]
+    );
+
+    let body = |at: usize| messages[at].get("body");
+    let breakpoints = body(3).get("breakpoints").as_array();
+    assert_eq!(breakpoints[0].get("verified"), &Json::Bool(true));
+    assert_eq!(breakpoints[1].get("verified"), &Json::Bool(false));
+    assert_eq!(body(5).get("reason").as_str(), Some("breakpoint"));
+
+    let frames = body(9).get("stackFrames").as_array();
+    let lines: Vec<_> = frames
+        .iter()
+        .map(|f| f.get("line").as_usize().unwrap())
+        .collect();
+    assert_eq!(lines, [3, 11]);
+    assert_eq!(frames[0].get("name").as_str(), Some("square"));
+    assert_eq!(
+        frames[1].get("source").get("name").as_str(),
+        Some("debug.onehour")
+    );
+
+    assert_eq!(body(10).get("scopes").as_array().len(), 3);
+    let stack = body(11).get("variables").as_array();
+    assert_eq!(stack[0].get("value").as_str(), Some("3"));
+    assert_eq!(body(12).get("result").as_str(), Some("3"));
+    assert_eq!(
+        messages[13].get("message").as_str(),
+        Some("`y` is not a variable")
+    );
+
+    assert_eq!(body(21).get("output").as_str(), Some("9\n"));
+    assert_eq!(body(22).get("exitCode").as_usize(), Some(9));
+    Ok(())
+}
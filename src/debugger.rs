@@ -0,0 +1,631 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use crate::command::{EngineError, Value};
+use crate::eval::{Evaluator, Undo};
+use crate::parser::Program;
+use crate::watch::{Action, Hit, Watch};
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint(usize),
+    Watchpoint(usize),
+    // Stepping backwards reached the first instruction, or the oldest one
+    // still in the history.
+    Start,
+    Finished(Value),
+    Failed(String),
+    Interrupted,
+}
+
+/// A variable write found in the history.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastWrite {
+    pub step: usize,
+    pub pc: usize,
+    pub value: Value,
+}
+
+/// How many instructions a `Debugger` can step back over unless told
+/// otherwise.
+pub const HISTORY_LIMIT: usize = 100_000;
+
+/// Runs a program one instruction at a time in either direction. The most
+/// recent instructions are kept as undo records, so the states they passed
+/// through can be returned to.
+pub struct Debugger<'p> {
+    program: &'p Program,
+    evaluator: Evaluator,
+    history: VecDeque<Undo>,
+    history_limit: usize,
+    // Instructions that ran but no longer fit in `history`.
+    forgotten: usize,
+    breakpoints: BTreeSet<usize>,
+    hits: Arc<Mutex<Vec<Hit>>>,
+    finished: Option<Stop>,
+}
+
+impl<'p> Debugger<'p> {
+    pub fn new(program: &'p Program, mut evaluator: Evaluator) -> Result<Self, EngineError> {
+        evaluator.start(program)?;
+        Ok(Self {
+            program,
+            evaluator,
+            history: VecDeque::new(),
+            history_limit: HISTORY_LIMIT,
+            forgotten: 0,
+            breakpoints: BTreeSet::new(),
+            hits: Arc::default(),
+            finished: None,
+        })
+    }
+
+    pub fn evaluator(&self) -> &Evaluator {
+        &self.evaluator
+    }
+
+    pub fn program(&self) -> &'p Program {
+        self.program
+    }
+
+    /// How many instructions have run to reach the current state.
+    pub fn steps(&self) -> usize {
+        self.forgotten + self.history.len()
+    }
+
+    /// Keeps only the last `limit` instructions for stepping backwards.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        self.trim_history();
+    }
+
+    fn trim_history(&mut self) {
+        while self.history.len() > self.history_limit {
+            self.history.pop_front();
+            self.forgotten += 1;
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Adds a watchpoint that stops `step` and `resume` when it fires, or
+    /// with `pause` false, only reports the hit through `take_hits`.
+    pub fn watch(&mut self, watch: Watch, pause: bool) -> usize {
+        let hits = self.hits.clone();
+        self.evaluator.watch(watch, move |hit| {
+            hits.lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(hit.clone());
+            match pause {
+                true => Action::Pause,
+                false => Action::Continue,
+            }
+        })
+    }
+
+    pub fn unwatch(&mut self, id: usize) -> bool {
+        self.evaluator.unwatch(id)
+    }
+
+    /// The watchpoint hits since the last call, oldest first.
+    pub fn take_hits(&mut self) -> Vec<Hit> {
+        std::mem::take(&mut *self.hits.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn step(&mut self) -> Stop {
+        if let Some(stop) = &self.finished {
+            return stop.clone();
+        }
+
+        let (undo, result) = self.evaluator.advance_undoable(self.program);
+        if let Err(EngineError::Interrupted { .. }) = result {
+            // Interrupts are noticed before the instruction runs, so there
+            // is nothing to undo.
+            self.evaluator.interrupt_handle().clear();
+            return Stop::Interrupted;
+        }
+        self.history.push_back(undo);
+        self.trim_history();
+
+        let stop = match result {
+            Ok(None) => return Stop::Step,
+            Err(EngineError::Paused { watch, .. }) => return Stop::Watchpoint(watch),
+            Ok(Some(value)) => Stop::Finished(value),
+            Err(e) => Stop::Failed(e.to_string()),
+        };
+        self.finished = Some(stop.clone());
+        stop
+    }
+
+    /// Runs until the next instruction is on a breakpoint or the program
+    /// ends.
+    pub fn resume(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step if self.breakpoints.contains(&self.evaluator.pc()) => {
+                    return Stop::Breakpoint(self.evaluator.pc());
+                }
+                Stop::Step => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    pub fn reverse_step(&mut self) -> Stop {
+        let Some(undo) = self.history.pop_back() else {
+            return Stop::Start;
+        };
+        self.evaluator.undo(undo);
+        self.finished = None;
+        Stop::Step
+    }
+
+    /// Runs backwards until the next instruction is on a breakpoint or the
+    /// program is back at its start.
+    pub fn reverse_resume(&mut self) -> Stop {
+        loop {
+            match self.reverse_step() {
+                Stop::Step if self.breakpoints.contains(&self.evaluator.pc()) => {
+                    return Stop::Breakpoint(self.evaluator.pc());
+                }
+                Stop::Step => {}
+                stop => return stop,
+            }
+        }
+    }
+
+    /// The most recent instruction that wrote `name`.
+    pub fn last_write(&self, name: &str) -> Option<LastWrite> {
+        self.history
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(step, undo)| {
+                let (written, value) = undo.written()?;
+                (written == name).then(|| LastWrite {
+                    step: self.forgotten + step + 1,
+                    pc: undo.pc(),
+                    value: value.clone(),
+                })
+            })
+    }
+
+    fn describe(&self, pc: usize) -> String {
+        let location = self.program.location(pc);
+        match self.program.commands.get(pc) {
+            Some(command) => format!(
+                "{}:{} (pc {}): {}",
+                location.file, location.line, pc, command
+            ),
+            None => format!("end of program (pc {})", pc),
+        }
+    }
+}
+
+const HELP: &str = "\
+step, s                 run one instruction
+continue, c             run to the next breakpoint
+reverse-step, rs        undo one instruction
+reverse-continue, rc    run backwards to the previous breakpoint
+break, b [FILE:]LINE    stop before the first instruction on LINE
+delete, d [FILE:]LINE   remove a breakpoint
+stack                   show the operand stack, top last
+vars                    show every variable
+print, p NAME           show one variable
+last-write NAME         show where NAME was last written
+watch NAME [TYPE]       stop when NAME changes, or is set to a TYPE
+watch depth N           stop when the stack depth crosses N
+trace ...               like watch, but only report
+unwatch ID              remove a watchpoint
+backtrace, bt           show the call stack
+quit, q                 stop debugging";
+
+/// An interactive debugger reading commands from `input`. `file` is the
+/// file breakpoints refer to when no file is given. Returns the exit code
+/// of the program if it ran to completion.
+pub fn repl(
+    program: &Program,
+    evaluator: Evaluator,
+    file: &str,
+    input: impl BufRead,
+    mut out: impl Write,
+) -> Result<i32, EngineError> {
+    let mut debugger = Debugger::new(program, evaluator)?;
+    let output = |e: std::io::Error| EngineError::Output(e.to_string());
+
+    writeln!(
+        out,
+        "stopped at {}",
+        debugger.describe(debugger.evaluator.pc())
+    )
+    .map_err(output)?;
+    write!(out, "(debug) ").map_err(output)?;
+    out.flush().map_err(output)?;
+
+    for line in input.lines() {
+        let line = line.map_err(|e| EngineError::Io(format!("debugger input: {}", e)))?;
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let operand = words.next();
+
+        let stop = match (command, operand) {
+            ("", _) => None,
+            ("step" | "s", _) => Some(debugger.step()),
+            ("continue" | "c", _) => Some(debugger.resume()),
+            ("reverse-step" | "rs", _) => Some(debugger.reverse_step()),
+            ("reverse-continue" | "rc", _) => Some(debugger.reverse_resume()),
+            ("break" | "b" | "delete" | "d", Some(at)) => {
+                let (at_file, at_line) = match at.rsplit_once(':') {
+                    Some((at_file, at_line)) => (at_file, at_line),
+                    None => (file, at),
+                };
+                match at_line.parse().ok().and_then(|n| program.pc_at(at_file, n)) {
+                    Some(pc) if command.starts_with('b') => {
+                        debugger.add_breakpoint(pc);
+                        writeln!(out, "breakpoint at {}", debugger.describe(pc))
+                    }
+                    Some(pc) if debugger.remove_breakpoint(pc) => {
+                        writeln!(out, "deleted breakpoint at {}", debugger.describe(pc))
+                    }
+                    Some(_) => writeln!(out, "no breakpoint at {}", at),
+                    None => writeln!(out, "no code at {}", at),
+                }
+                .map_err(output)?;
+                None
+            }
+            ("watch" | "trace", Some(name)) => {
+                let watch = match (name, words.next()) {
+                    (name, None) => Watch::Change(name.into()),
+                    ("depth", Some(n)) => match n.parse() {
+                        Ok(depth) => Watch::Depth(depth),
+                        Err(_) => Watch::Type(name.into(), n.into()),
+                    },
+                    (name, Some(type_name)) => Watch::Type(name.into(), type_name.into()),
+                };
+                let description = watch.to_string();
+                let id = debugger.watch(watch, command == "watch");
+                writeln!(out, "watchpoint {}: {}", id, description).map_err(output)?;
+                None
+            }
+            ("unwatch", Some(id)) => {
+                match id.parse().is_ok_and(|id| debugger.unwatch(id)) {
+                    true => writeln!(out, "deleted watchpoint {}", id),
+                    false => writeln!(out, "no watchpoint {}", id),
+                }
+                .map_err(output)?;
+                None
+            }
+            ("stack", _) => {
+                for value in debugger.evaluator.stack() {
+                    writeln!(out, "  {}", value).map_err(output)?;
+                }
+                None
+            }
+            ("vars", _) => {
+                let mut vars: Vec<_> = debugger.evaluator.vars().iter().collect();
+                vars.sort_by(|a, b| a.0.cmp(b.0));
+                for (name, value) in vars {
+                    writeln!(out, "  {} = {}", name, value).map_err(output)?;
+                }
+                None
+            }
+            ("print" | "p", Some(name)) => {
+                match debugger.evaluator.vars().get(name) {
+                    Some(value) => writeln!(out, "{} = {}", name, value),
+                    None => writeln!(out, "`{}` is not set", name),
+                }
+                .map_err(output)?;
+                None
+            }
+            ("last-write", Some(name)) => {
+                match debugger.last_write(name) {
+                    Some(write) => writeln!(
+                        out,
+                        "{} = {} written at step {} by {}",
+                        name,
+                        write.value,
+                        write.step,
+                        debugger.describe(write.pc)
+                    ),
+                    None => writeln!(out, "`{}` has not been written", name),
+                }
+                .map_err(output)?;
+                None
+            }
+            ("backtrace" | "bt", _) => {
+                for frame in debugger.evaluator.backtrace(program) {
+                    writeln!(out, "  {}", frame).map_err(output)?;
+                }
+                None
+            }
+            ("quit" | "q", _) => break,
+            ("help" | "h", _) => {
+                writeln!(out, "{}", HELP).map_err(output)?;
+                None
+            }
+            _ => {
+                writeln!(out, "unknown command `{}`; try `help`", line.trim()).map_err(output)?;
+                None
+            }
+        };
+
+        for hit in debugger.take_hits() {
+            writeln!(out, "{} at {}", hit, debugger.describe(hit.pc)).map_err(output)?;
+        }
+        match stop {
+            None => {}
+            Some(Stop::Finished(value)) => {
+                writeln!(out, "program finished with {}", value).map_err(output)?
+            }
+            Some(Stop::Failed(message)) => {
+                writeln!(out, "program failed: {}", message).map_err(output)?
+            }
+            Some(stop) => {
+                let why = match stop {
+                    Stop::Breakpoint(_) => "breakpoint, ",
+                    Stop::Watchpoint(_) => "watchpoint, ",
+                    Stop::Start => "start of program, ",
+                    Stop::Interrupted => "interrupted, ",
+                    _ => "",
+                };
+                let at = debugger.describe(debugger.evaluator.pc());
+                writeln!(out, "{}stopped at {}", why, at).map_err(output)?
+            }
+        }
+        write!(out, "(debug) ").map_err(output)?;
+        out.flush().map_err(output)?;
+    }
+
+    writeln!(out).map_err(output)?;
+    Ok(match &debugger.finished {
+        Some(Stop::Finished(value)) => value.exit_code(),
+        Some(_) => 1,
+        None => 0,
+    })
+}
+
+#[test]
+fn test_reverse_debugging() -> Result<(), EngineError> {
+    use crate::parser::Parser;
+
+    let input = "func double\npush 2\nmul\nret\nfunc main\npush 1\nstore x\npush 3\ncall double\nstore x\ntry fail\npush 0\npush 1\ndiv\nendtry\nfail:\nload x\nend";
+    let program = Parser::new().parse(input)?;
+    let mut debugger = Debugger::new(&program, Evaluator::new())?;
+
+    let breakpoint = program.pc_at("<input>", 17).unwrap();
+    debugger.add_breakpoint(breakpoint);
+    assert_eq!(debugger.resume(), Stop::Breakpoint(breakpoint));
+    assert!(matches!(debugger.evaluator().stack(), [Value::Error(_)]));
+
+    let write = debugger.last_write("x").unwrap();
+    assert_eq!(write.value, Value::Int(6));
+    assert_eq!(program.location(write.pc).line, 10);
+
+    assert_eq!(debugger.resume(), Stop::Finished(Value::Int(6)));
+    assert_eq!(debugger.reverse_resume(), Stop::Breakpoint(breakpoint));
+
+    let mut lines = vec![];
+    while debugger.reverse_step() == Stop::Step {
+        lines.push(program.location(debugger.evaluator().pc()).line);
+    }
+    assert_eq!(lines, [14, 13, 12, 11, 10, 4, 3, 2, 9, 8, 7, 6]);
+    assert_eq!(debugger.steps(), 0);
+    assert!(debugger.evaluator().stack().is_empty());
+    assert!(debugger.evaluator().vars().is_empty());
+    assert!(debugger.last_write("x").is_none());
+
+    assert_eq!(debugger.resume(), Stop::Breakpoint(breakpoint));
+    assert_eq!(debugger.last_write("x").unwrap().value, Value::Int(6));
+    Ok(())
+}
+
+#[test]
+fn test_repl() -> Result<(), EngineError> {
+    use crate::parser::Parser;
+
+    let program =
+        Parser::new().parse("func main\npush 1\nstore x\npush 2\nstore x\nload x\nend")?;
+    let commands = "b 5\nc\nlast-write x\nc\nrc\nrs\np x\nb 9\nq\n";
+    let mut out = vec![];
+    let code = repl(
+        &program,
+        Evaluator::new(),
+        "<input>",
+        commands.as_bytes(),
+        &mut out,
+    )?;
+    let out = String::from_utf8(out).unwrap();
+
+    assert_eq!(code, 0);
+    assert!(out.contains("breakpoint, stopped at <input>:5 (pc 3): store x"));
+    assert!(out.contains("x = 1 written at step 2 by <input>:3 (pc 1): store x"));
+    assert!(out.contains("program finished with 2"));
+    assert!(out.contains("stopped at <input>:4 (pc 2): push 2"));
+    assert!(out.contains("x = 1\n"));
+    assert!(out.contains("no code at 9"));
+    Ok(())
+}
+
+#[test]
+fn test_watchpoints() -> Result<(), EngineError> {
+    use crate::parser::Parser;
+
+    let program = Parser::new()
+        .parse("func main\npush 1\nstore x\npush 1\nstore x\npush \"a\"\nstore x\nend")?;
+    let commands = "trace depth 1\nwatch x\nc\nc\nrs\nunwatch 2\nwatch x string\nc\nc\n";
+    let mut out = vec![];
+    repl(
+        &program,
+        Evaluator::new(),
+        "<input>",
+        commands.as_bytes(),
+        &mut out,
+    )?;
+    let out = String::from_utf8(out).unwrap();
+
+    let expected = [
+        "watchpoint 1 (stack depth crosses 1): stack depth went from 0 to 1 at <input>:2",
+        "watchpoint 2 (`x` changes): `x` set to 1 at <input>:3",
+        "watchpoint, stopped at <input>:4",
+        "watchpoint 2 (`x` changes): `x` changed from 1 to \"a\" at <input>:7",
+        "stopped at <input>:7",
+        "deleted watchpoint 2",
+        "watchpoint 3 (`x` is set to a string): `x` changed from 1 to \"a\" at <input>:7",
+        "program finished with void",
+    ];
+    let mut rest = out.as_str();
+    for line in expected {
+        let at = rest
+            .find(line)
+            .unwrap_or_else(|| panic!("`{}` not in\n{}", line, out));
+        rest = &rest[at + line.len()..];
+    }
+    Ok(())
+}
+
+#[test]
+fn test_reverse_restores_every_state() -> Result<(), EngineError> {
+    use crate::parser::Parser;
+
+    let input = "\
+func next
+getcap 0
+push 1
+add
+dup
+setcap 0
+ret
+func gen
+pop
+push 1
+yield
+pop
+push 2
+yield
+pop
+push \"gen failed\"
+throw
+ret
+func producer
+loop:
+dup
+load chan
+send
+push -1
+add
+dup
+jp loop
+ret
+func garbage
+loop:
+dup
+jz done
+dup
+closure next 1
+pop
+push -1
+add
+jmp loop
+done:
+ret
+func main
+push 0
+closure next 1
+store counter
+load counter
+icall
+pop
+pushfn gen
+coroutine
+store co
+push 0
+load co
+resume
+push 0
+load co
+resume
+add
+store yielded
+try dead
+push 0
+load co
+resume
+endtry
+dead:
+errmsg
+store message
+push 1
+channel
+store chan
+push 3
+pushfn producer
+spawn
+store task
+load chan
+recv
+load chan
+recv
+add
+load chan
+recv
+add
+store received
+load task
+join
+pop
+push 300
+call garbage
+pop
+load counter
+icall
+end";
+    let program = Parser::new().parse(input)?;
+    let mut debugger = Debugger::new(&program, Evaluator::new())?;
+    let snapshot = |debugger: &Debugger| debugger.evaluator().snapshot(&program);
+
+    let mut states = vec![snapshot(&debugger)];
+    while debugger.step() == Stop::Step {
+        states.push(snapshot(&debugger));
+    }
+    states.push(snapshot(&debugger));
+    assert_eq!(debugger.step(), Stop::Finished(Value::Int(2)));
+    let vars = debugger.evaluator().vars();
+    assert_eq!(vars["yielded"], Value::Int(3));
+    assert_eq!(vars["message"], Value::String("gen failed".into()));
+    assert_eq!(vars["received"], Value::Int(6));
+    assert!(debugger.evaluator().live_objects() < 300);
+
+    // Every state is restored exactly on the way back, and running forward
+    // again passes through the same states.
+    let forward = states.clone();
+    states.pop();
+    while debugger.reverse_step() == Stop::Step {
+        assert_eq!(Some(snapshot(&debugger)), states.pop());
+    }
+    assert!(states.is_empty());
+    for state in &forward[1..] {
+        debugger.step();
+        assert_eq!(&snapshot(&debugger), state);
+    }
+
+    let steps = debugger.steps();
+    debugger.set_history_limit(5);
+    assert_eq!(debugger.steps(), steps);
+    assert_eq!(debugger.reverse_resume(), Stop::Start);
+    assert_eq!(debugger.steps(), steps - 5);
+    assert_eq!(&snapshot(&debugger), &forward[steps - 5]);
+    Ok(())
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub width: usize,
+    pub message: String,
+    pub source: String,
+    pub help: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(
+            f,
+            "{}--> {}:{}:{}",
+            gutter, self.file, self.line, self.column
+        )?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+        write!(
+            f,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(self.column.saturating_sub(1)),
+            "^".repeat(self.width.max(1))
+        )?;
+        if let Some(help) = &self.help {
+            write!(f, "\n{} = help: {}", gutter, help)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current.push(
+                (previous[j] + cost)
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1),
+            );
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+
+    candidates
+        .into_iter()
+        .map(|candidate| (distance(name, candidate), candidate))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, candidate)| candidate)
+}
+
+#[test]
+fn test_suggest() {
+    let known = ["push", "pop", "add", "sub", "jz", "jp"];
+
+    assert_eq!(suggest("pusj", known), Some("push"));
+    assert_eq!(suggest("ad", known), Some("add"));
+    assert_eq!(suggest("frobnicate", known), None);
+}
+
+#[test]
+fn test_render() {
+    let diagnostic = Diagnostic {
+        file: "main.onehour".into(),
+        line: 12,
+        column: 5,
+        width: 4,
+        message: "unknown command `pusj`".into(),
+        source: "    pusj 10".into(),
+        help: Some("did you mean `push`?".into()),
+    };
+
+    assert_eq!(
+        diagnostic.to_string(),
+        "error: unknown command `pusj`\n  --> main.onehour:12:5\n   |\n12 |     pusj 10\n   |     ^^^^\n   = help: did you mean `push`?"
+    );
+}
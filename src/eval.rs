@@ -1,13 +1,396 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::command::{Command, EngineError, Value};
+use crate::bigint::BigInt;
+use crate::command::{Command, EngineError, ErrorValue, Frame, Overflow, Value};
+use crate::heap::{handle_of, Channel, Closure, Collected, Coroutine, Heap, Object, Status};
+use crate::native::{builtins, printer, Native};
 use crate::parser::Program;
+use crate::replay::{Call, Journal};
+use crate::snapshot::{invalid, Reader, Writer};
+use crate::store::Store;
+use crate::watch::{Action, Event, Hit, Watch, Watchpoint};
+
+// Results larger than this are treated as overflow rather than exhausting
+// memory (about 315 000 decimal digits).
+const MAX_BIGINT_BITS: u64 = 1 << 20;
+
+const MAX_CALL_DEPTH: usize = 100_000;
+
+// How many instructions a task runs before the scheduler moves on.
+const TIME_SLICE: usize = 1000;
+
+const MAIN_TASK: usize = 0;
+
+const SNAPSHOT: &str = "onehour-snapshot";
+
+// How many instructions run between checks for an interrupt.
+const INTERRUPT_INTERVAL: u64 = 1024;
+
+/// Stops a running evaluation from another thread. Every evaluation using
+/// the handle returns `EngineError::Interrupted` shortly after `interrupt` is
+/// called.
+#[derive(Clone, Default, Debug)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, AtomicOrdering::SeqCst);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Lets evaluations using the handle run again after an interrupt.
+    pub fn clear(&self) {
+        self.0.store(false, AtomicOrdering::SeqCst);
+    }
+}
+
+pub enum Step {
+    Continue,
+    // The instruction cannot proceed until another task runs; it is retried
+    // when the current task is next scheduled.
+    Blocked,
+    Done(Value),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Wait {
+    Join(usize),
+    Send(usize),
+    Recv(usize),
+}
+
+// A green thread. The running task's state lives in the `Evaluator` fields
+// and is swapped in and out by the scheduler.
+#[derive(Debug, Default)]
+struct Task {
+    fiber: Fiber,
+    resumers: Vec<(usize, Fiber)>,
+    wait: Option<Wait>,
+    result: Option<Value>,
+}
+
+// An active `try`: where to resume, and how much of the operand stack and
+// call stack to keep when unwinding to it.
+#[derive(Debug, Clone)]
+struct Handler {
+    target: usize,
+    stack: usize,
+    frames: usize,
+    env: Option<usize>,
+}
+
+/// The execution state of a coroutine. The running one lives in the
+/// `Evaluator` fields of the same names and is swapped in and out on
+/// `resume` and `yield`.
+#[derive(Debug, Default, Clone)]
+pub struct Fiber {
+    stack: Vec<Value>,
+    pc: usize,
+    pc_stack: Vec<usize>,
+    env: Option<usize>,
+    env_stack: Vec<Option<usize>>,
+    handlers: Vec<Handler>,
+}
+
+impl Fiber {
+    pub fn trace(&self, pending: &mut Vec<usize>) {
+        pending.extend(self.stack.iter().filter_map(handle_of));
+        pending.extend(self.env_stack.iter().chain([&self.env]).flatten());
+    }
+
+    pub fn save(&self, out: &mut Writer) {
+        out.word(self.pc);
+        out.option(self.env);
+        out.values(&self.stack);
+        out.list(&self.pc_stack, |out, pc| out.word(pc));
+        out.list(&self.env_stack, |out, env| out.option(*env));
+        out.list(&self.handlers, |out, handler| {
+            out.word(handler.target);
+            out.word(handler.stack);
+            out.word(handler.frames);
+            out.option(handler.env);
+        });
+    }
+
+    pub fn load(input: &mut Reader) -> Result<Self, EngineError> {
+        Ok(Self {
+            pc: input.number()?,
+            env: input.option()?,
+            stack: input.values()?,
+            pc_stack: input.list(|input| input.number())?,
+            env_stack: input.list(|input| input.option())?,
+            handlers: input.list(|input| {
+                Ok(Handler {
+                    target: input.number()?,
+                    stack: input.number()?,
+                    frames: input.number()?,
+                    env: input.option()?,
+                })
+            })?,
+        })
+    }
+}
+
+fn save_resumers(out: &mut Writer, resumers: &[(usize, Fiber)]) {
+    out.list(resumers, |out, (handle, fiber)| {
+        out.word(handle);
+        fiber.save(out);
+    });
+}
+
+fn load_resumers(input: &mut Reader) -> Result<Vec<(usize, Fiber)>, EngineError> {
+    input.list(|input| Ok((input.number()?, Fiber::load(input)?)))
+}
+
+fn save_wait(out: &mut Writer, wait: Option<Wait>) {
+    match wait {
+        None => out.word("-"),
+        Some(Wait::Join(id)) => out.word(format!("join {}", id)),
+        Some(Wait::Send(handle)) => out.word(format!("send {}", handle)),
+        Some(Wait::Recv(handle)) => out.word(format!("recv {}", handle)),
+    }
+}
+
+fn load_wait(input: &mut Reader) -> Result<Option<Wait>, EngineError> {
+    let wait = match input.word()?.as_str() {
+        "-" => return Ok(None),
+        "join" => Wait::Join(input.number()?),
+        "send" => Wait::Send(input.number()?),
+        "recv" => Wait::Recv(input.number()?),
+        word => return Err(invalid(format!("unknown wait `{}`", word))),
+    };
+    Ok(Some(wait))
+}
+
+// Checks that a restored snapshot only refers to heap objects, tasks and
+// code that exist, so a corrupt file fails to load instead of panicking later.
+struct Check<'a> {
+    program: &'a Program,
+    heap: &'a Heap,
+    tasks: usize,
+}
+
+impl Check<'_> {
+    fn handle(&self, handle: usize, kind: &str) -> Result<(), EngineError> {
+        let matches = match self.heap.object(handle) {
+            Some(Object::Closure(_)) => kind == "closure",
+            Some(Object::Coroutine(_)) => kind == "coroutine",
+            Some(Object::Channel(_)) => kind == "channel",
+            None => false,
+        };
+        match matches {
+            true => Ok(()),
+            false => Err(invalid(format!("no {} with handle {}", kind, handle))),
+        }
+    }
+
+    fn pc(&self, pc: usize) -> Result<(), EngineError> {
+        match pc <= self.program.commands.len() {
+            true => Ok(()),
+            false => Err(invalid(format!("no instruction {}", pc))),
+        }
+    }
+
+    fn task(&self, id: usize) -> Result<(), EngineError> {
+        match id < self.tasks {
+            true => Ok(()),
+            false => Err(invalid(format!("no task {}", id))),
+        }
+    }
+
+    fn value(&self, value: &Value) -> Result<(), EngineError> {
+        match value {
+            Value::Closure(handle) => self.handle(*handle, "closure"),
+            Value::Coroutine(handle) => self.handle(*handle, "coroutine"),
+            Value::Channel(handle) => self.handle(*handle, "channel"),
+            Value::Task(id) => self.task(*id),
+            _ => Ok(()),
+        }
+    }
+
+    fn fiber(&self, fiber: &Fiber) -> Result<(), EngineError> {
+        if fiber.env_stack.len() != fiber.pc_stack.len() {
+            return Err(invalid("mismatched call stacks"));
+        }
+        self.pc(fiber.pc)?;
+        fiber.pc_stack.iter().try_for_each(|&pc| self.pc(pc))?;
+        fiber.stack.iter().try_for_each(|value| self.value(value))?;
+        for env in fiber.env_stack.iter().chain([&fiber.env]).flatten() {
+            self.handle(*env, "closure")?;
+        }
+        for handler in &fiber.handlers {
+            self.pc(handler.target)?;
+            if let Some(env) = handler.env {
+                self.handle(env, "closure")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn running(&self, resumers: &[(usize, Fiber)], wait: &Option<Wait>) -> Result<(), EngineError> {
+        for (handle, fiber) in resumers {
+            self.handle(*handle, "coroutine")?;
+            self.fiber(fiber)?;
+        }
+        match wait {
+            Some(Wait::Join(id)) => self.task(*id),
+            Some(Wait::Send(handle) | Wait::Recv(handle)) => self.handle(*handle, "channel"),
+            None => Ok(()),
+        }
+    }
+
+    fn object(&self, object: &Object) -> Result<(), EngineError> {
+        match object {
+            Object::Closure(closure) => {
+                if !self.program.functions.contains_key(&closure.function) {
+                    return Err(invalid(format!("unknown function `{}`", closure.function)));
+                }
+                closure
+                    .captures
+                    .iter()
+                    .try_for_each(|value| self.value(value))
+            }
+            Object::Coroutine(coroutine) => self.fiber(&coroutine.fiber),
+            Object::Channel(channel) => {
+                channel.queue.iter().try_for_each(|value| self.value(value))
+            }
+        }
+    }
+}
+
+// One change made by an instruction, as `undo` needs it to take the change
+// back. Frames, handlers, fibers and heap objects the instruction removed are
+// moved in here; values it popped or received are cloned, since the
+// instruction itself goes on to use them.
+enum Op {
+    // The running stack went down to `floor`, losing `sunk` (top first),
+    // before whatever is above `floor` now was pushed; the fiber was at `pc`.
+    Stack {
+        floor: usize,
+        sunk: Vec<Value>,
+        pc: usize,
+    },
+    Call,
+    Env(Option<usize>),
+    Ret {
+        pc: usize,
+        env: Option<usize>,
+        handlers: Vec<Handler>,
+    },
+    Try,
+    EndTry(Handler),
+    Catch {
+        handler: Handler,
+        pc_stack: Vec<usize>,
+        env_stack: Vec<Option<usize>>,
+        env: Option<usize>,
+    },
+    Resume(usize),
+    // `fiber` is the coroutine's when it died, as nothing else keeps it.
+    Suspend {
+        handle: usize,
+        fiber: Option<Fiber>,
+    },
+    Reschedule {
+        from: usize,
+        wait: Option<Wait>,
+    },
+    Spawn,
+    Wait(Option<Wait>),
+    Finished(usize),
+    Alloc {
+        handle: usize,
+        reused: bool,
+    },
+    Collect(Collected),
+    Capture {
+        handle: usize,
+        slot: usize,
+        old: Value,
+    },
+    Sent(usize),
+    Received(usize, Value),
+}
+
+// The changes made so far by the instruction `advance_undoable` is running.
+#[derive(Default)]
+struct Trail {
+    ops: Vec<Op>,
+    floor: usize,
+    sunk: Vec<Value>,
+    pc: usize,
+}
+
+impl Trail {
+    // Ends the part of the instruction run by one fiber; the next one is at
+    // `pc` with a stack `floor` deep.
+    fn cut(&mut self, floor: usize, pc: usize) {
+        let floor = std::mem::replace(&mut self.floor, floor);
+        let pc = std::mem::replace(&mut self.pc, pc);
+        let sunk = std::mem::take(&mut self.sunk);
+        self.ops.push(Op::Stack { floor, sunk, pc });
+    }
+}
+
+/// What `Evaluator::undo` needs to take back one `advance`.
+pub struct Undo {
+    pc: usize,
+    ticks: usize,
+    written: Option<(String, Value)>,
+    old: Option<Value>,
+    ops: Vec<Op>,
+}
+
+impl Undo {
+    /// Where the instruction that was undone ran.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The variable the instruction wrote, and the value it wrote.
+    pub fn written(&self) -> Option<(&str, &Value)> {
+        self.written
+            .as_ref()
+            .map(|(name, value)| (name.as_str(), value))
+    }
+}
 
 pub struct Evaluator {
     vars: HashMap<String, Value>,
     stack: Vec<Value>,
     pc: usize,
     pc_stack: Vec<usize>,
+    // The closure each frame runs in, kept in step with `pc_stack`.
+    env: Option<usize>,
+    env_stack: Vec<Option<usize>>,
+    heap: Heap,
+    handlers: Vec<Handler>,
+    // The coroutines being run, innermost last, each with the state of
+    // whoever resumed it.
+    resumers: Vec<(usize, Fiber)>,
+    wait: Option<Wait>,
+    tasks: Vec<Task>,
+    current: usize,
+    ticks: usize,
+    executed: u64,
+    // Set after a native call so the next instruction checks the deadline.
+    overdue_check: bool,
+    interrupt: InterruptHandle,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    store: Option<Store>,
+    journal: Option<Journal>,
+    watches: Vec<Watchpoint>,
+    next_watch: usize,
+    trail: Option<Trail>,
+    natives: HashMap<String, Native>,
 }
 
 impl Evaluator {
@@ -17,7 +400,253 @@ impl Evaluator {
             stack: vec![],
             pc: 0,
             pc_stack: vec![],
+            env: None,
+            env_stack: vec![],
+            heap: Heap::new(),
+            handlers: vec![],
+            resumers: vec![],
+            wait: None,
+            tasks: vec![Task::default()],
+            current: MAIN_TASK,
+            ticks: 0,
+            executed: 0,
+            overdue_check: false,
+            interrupt: InterruptHandle::default(),
+            timeout: None,
+            deadline: None,
+            store: None,
+            journal: None,
+            watches: vec![],
+            next_watch: 1,
+            trail: None,
+            natives: builtins(),
+        }
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, EngineError> + Send + Sync + 'static,
+    ) {
+        self.natives
+            .insert(name.into(), Native::new(arity, function));
+    }
+
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Makes this evaluator answer to an existing handle, so one handle can
+    /// stop several evaluators.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Limits every later `evaluate` to `timeout` of wall-clock time.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Loads `.persist` variables from `store` when evaluation starts and
+    /// writes them back when it finishes successfully, that is without an
+    /// error and with a result whose exit code is 0.
+    pub fn set_store(&mut self, store: Option<Store>) {
+        self.store = store;
+    }
+
+    /// Calls `callback` whenever `watch` fires, right after the instruction
+    /// responsible. Returns an id for `unwatch`.
+    pub fn watch(
+        &mut self,
+        watch: Watch,
+        callback: impl FnMut(&Hit) -> Action + Send + 'static,
+    ) -> usize {
+        let id = self.next_watch;
+        self.next_watch += 1;
+        self.watches.push(Watchpoint {
+            id,
+            watch,
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    pub fn unwatch(&mut self, id: usize) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|watchpoint| watchpoint.id != id);
+        self.watches.len() != before
+    }
+
+    /// Logs every native call from now on; see `recording`.
+    pub fn record(&mut self) {
+        self.journal = Some(Journal::Record(vec![]));
+    }
+
+    /// The native calls logged since `record`, in the format `replay` reads.
+    pub fn recording(&self) -> Option<String> {
+        self.journal.as_ref().map(Journal::save)
+    }
+
+    /// Answers native calls from a recording instead of calling the host.
+    /// Evaluation fails with `EngineError::Diverged` as soon as the program
+    /// makes a call the recorded run did not.
+    pub fn replay(&mut self, recording: &str) -> Result<(), EngineError> {
+        self.journal = Some(Journal::load(recording)?);
+        Ok(())
+    }
+
+    fn load_persistent(&mut self, program: &Program) -> Result<(), EngineError> {
+        let mut stored = match &self.store {
+            Some(store) if !program.persistent.is_empty() => store.load()?,
+            _ => HashMap::new(),
+        };
+        for (name, initial) in &program.persistent {
+            let value = stored.remove(name).unwrap_or_else(|| initial.clone());
+            self.vars.insert(name.clone(), value);
+        }
+        Ok(())
+    }
+
+    fn finish(&self, program: &Program, result: &Value) -> Result<(), EngineError> {
+        if let Some(journal) = &self.journal {
+            journal.finish()?;
+        }
+        self.commit_persistent(program, result)
+    }
+
+    fn commit_persistent(&self, program: &Program, result: &Value) -> Result<(), EngineError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        if program.persistent.is_empty() || result.exit_code() != 0 {
+            return Ok(());
+        }
+
+        let updates = program
+            .persistent
+            .iter()
+            .filter_map(|(name, _)| Some((name.clone(), self.vars.get(name)?.clone())))
+            .collect();
+        store.commit(updates)
+    }
+
+    /// Serializes everything needed to continue the evaluation later with
+    /// `restore` and `resume`. Native functions are not saved and have to be
+    /// registered again.
+    pub fn snapshot(&self, program: &Program) -> String {
+        let mut out = Writer::new(SNAPSHOT);
+        out.word(format!("{:016x}", program.hash()));
+        out.line();
+
+        let mut vars: Vec<_> = self.vars.iter().collect();
+        vars.sort_by_key(|(name, _)| *name);
+        out.list(&vars, |out, (name, value)| {
+            out.string(name);
+            out.value(value);
+        });
+        out.line();
+
+        let running = Fiber {
+            stack: self.stack.clone(),
+            pc: self.pc,
+            pc_stack: self.pc_stack.clone(),
+            env: self.env,
+            env_stack: self.env_stack.clone(),
+            handlers: self.handlers.clone(),
+        };
+        running.save(&mut out);
+        save_resumers(&mut out, &self.resumers);
+        save_wait(&mut out, self.wait);
+        out.line();
+
+        out.word(self.current);
+        out.word(self.ticks);
+        out.list(&self.tasks, |out, task| {
+            task.fiber.save(out);
+            save_resumers(out, &task.resumers);
+            save_wait(out, task.wait);
+            match &task.result {
+                Some(result) => {
+                    out.word("done");
+                    out.value(result);
+                }
+                None => out.word("-"),
+            }
+            out.line();
+        });
+
+        self.heap.save(&mut out);
+        out.finish()
+    }
+
+    /// Rebuilds an evaluator from a `snapshot` of one evaluating `program`.
+    pub fn restore(program: &Program, snapshot: &str) -> Result<Self, EngineError> {
+        let mut input = Reader::new(snapshot, SNAPSHOT)?;
+        if input.word()? != format!("{:016x}", program.hash()) {
+            return Err(EngineError::Snapshot(
+                "snapshot was taken from a different program".into(),
+            ));
+        }
+        let mut evaluator = Self::new();
+
+        evaluator.vars = input
+            .list(|input| Ok((input.string()?, input.value()?)))?
+            .into_iter()
+            .collect();
+
+        let mut running = Fiber::load(&mut input)?;
+        evaluator.resumers = load_resumers(&mut input)?;
+        evaluator.wait = load_wait(&mut input)?;
+
+        evaluator.current = input.number()?;
+        evaluator.ticks = input.number()?;
+        evaluator.tasks = input.list(|input| {
+            Ok(Task {
+                fiber: Fiber::load(input)?,
+                resumers: load_resumers(input)?,
+                wait: load_wait(input)?,
+                result: match input.word()?.as_str() {
+                    "-" => None,
+                    _ => Some(input.value()?),
+                },
+            })
+        })?;
+        if evaluator.current >= evaluator.tasks.len() {
+            return Err(invalid("no running task"));
+        }
+
+        evaluator.heap = Heap::load(&mut input)?;
+        input.finish()?;
+
+        let check = Check {
+            program,
+            heap: &evaluator.heap,
+            tasks: evaluator.tasks.len(),
+        };
+        check.fiber(&running)?;
+        check.running(&evaluator.resumers, &evaluator.wait)?;
+        for value in evaluator.vars.values() {
+            check.value(value)?;
+        }
+        for task in &evaluator.tasks {
+            check.fiber(&task.fiber)?;
+            check.running(&task.resumers, &task.wait)?;
+            task.result
+                .iter()
+                .try_for_each(|value| check.value(value))?;
         }
+        for object in evaluator.heap.objects() {
+            check.object(object)?;
+        }
+
+        evaluator.switch(&mut running);
+        Ok(evaluator)
+    }
+
+    /// Sends `print` output to `out` instead of standard output.
+    pub fn set_output(&mut self, out: impl std::io::Write + Send + 'static) {
+        self.natives.insert("print".into(), printer(out));
     }
 
     fn push(&mut self, value: Value) -> Result<(), EngineError> {
@@ -28,184 +657,1473 @@ impl Evaluator {
     fn pop(&mut self) -> Result<Value, EngineError> {
         let result = self.stack.pop();
         match result {
-            Some(x) => Ok(x),
+            Some(x) => {
+                if let Some(trail) = &mut self.trail {
+                    if self.stack.len() < trail.floor {
+                        trail.floor = self.stack.len();
+                        trail.sunk.push(x.clone());
+                    }
+                }
+                Ok(x)
+            }
             None => Err(EngineError::EmptyStack),
         }
     }
 
-    fn add(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
-        match (lhs, rhs) {
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a + b)),
+    // Like `Vec::split_off`, but keeps what `undo` needs.
+    fn split_stack(&mut self, at: usize) -> Vec<Value> {
+        let at = at.min(self.stack.len());
+        let removed = self.stack.split_off(at);
+        if let Some(trail) = &mut self.trail {
+            if at < trail.floor {
+                let below = &removed[..trail.floor - at];
+                trail.sunk.extend(below.iter().rev().cloned());
+                trail.floor = at;
+            }
+        }
+        removed
+    }
+
+    fn log(&mut self, op: Op) {
+        if let Some(trail) = &mut self.trail {
+            trail.ops.push(op);
+        }
+    }
+
+    fn peek(&self, depth: usize) -> Result<&Value, EngineError> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|index| &self.stack[index])
+            .ok_or(EngineError::EmptyStack)
+    }
+
+    fn int_result(
+        &self,
+        mode: Overflow,
+        checked: Option<i64>,
+        wrapping: i64,
+        saturating: i64,
+        exact: impl FnOnce() -> BigInt,
+    ) -> Result<Value, EngineError> {
+        match (mode, checked) {
+            (Overflow::Checked, Some(n)) => Ok(Value::Int(n)),
+            (Overflow::Checked, None) => self.big_result(mode, exact()),
+            (Overflow::Wrapping, _) => Ok(Value::Int(wrapping)),
+            (Overflow::Saturating, _) => Ok(Value::Int(saturating)),
+        }
+    }
+
+    fn big_result(&self, mode: Overflow, exact: BigInt) -> Result<Value, EngineError> {
+        match mode {
+            Overflow::Checked if exact.bits() > MAX_BIGINT_BITS => Err(EngineError::Overflow),
+            Overflow::Checked => Ok(Value::from(exact)),
+            Overflow::Wrapping => Ok(Value::Int(exact.wrapping_to_i64())),
+            Overflow::Saturating => Ok(Value::Int(exact.saturating_to_i64())),
+        }
+    }
+
+    fn big(&self, value: Value) -> Result<BigInt, EngineError> {
+        match value {
+            Value::Int(n) => Ok(BigInt::from(n)),
+            Value::BigInt(n) => Ok(n),
             _ => Err(EngineError::MismatchType),
         }
     }
 
-    fn sub(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+    fn add(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
         match (lhs, rhs) {
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a - b)),
-            _ => Err(EngineError::MismatchType),
+            (Value::Int(a), Value::Int(b)) => self.int_result(
+                mode,
+                a.checked_add(b),
+                a.wrapping_add(b),
+                a.saturating_add(b),
+                || &BigInt::from(a) + &BigInt::from(b),
+            ),
+            (lhs, rhs) => self.big_result(mode, &self.big(lhs)? + &self.big(rhs)?),
         }
     }
 
-    fn mul(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+    fn sub(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
         match (lhs, rhs) {
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a * b)),
-            _ => Err(EngineError::MismatchType),
+            (Value::Int(a), Value::Int(b)) => self.int_result(
+                mode,
+                a.checked_sub(b),
+                a.wrapping_sub(b),
+                a.saturating_sub(b),
+                || &BigInt::from(a) - &BigInt::from(b),
+            ),
+            (lhs, rhs) => self.big_result(mode, &self.big(lhs)? - &self.big(rhs)?),
         }
     }
 
-    fn div(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+    fn mul(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
         match (lhs, rhs) {
-            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a / b)),
-            _ => Err(EngineError::MismatchType),
+            (Value::Int(a), Value::Int(b)) => self.int_result(
+                mode,
+                a.checked_mul(b),
+                a.wrapping_mul(b),
+                a.saturating_mul(b),
+                || &BigInt::from(a) * &BigInt::from(b),
+            ),
+            (lhs, rhs) => {
+                let (a, b) = (self.big(lhs)?, self.big(rhs)?);
+                if a.bits() + b.bits() > MAX_BIGINT_BITS + 1 && mode == Overflow::Checked {
+                    return Err(EngineError::Overflow);
+                }
+                self.big_result(mode, &a * &b)
+            }
+        }
+    }
+
+    fn div(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => Err(EngineError::DivisionByZero),
+            (Value::Int(a), Value::Int(b)) => self.int_result(
+                mode,
+                a.checked_div(b),
+                a.wrapping_div(b),
+                a.saturating_div(b),
+                || BigInt::from(a).div_rem(&BigInt::from(b)).unwrap().0,
+            ),
+            (lhs, rhs) => match self.big(lhs)?.div_rem(&self.big(rhs)?) {
+                Some((quotient, _)) => self.big_result(mode, quotient),
+                None => Err(EngineError::DivisionByZero),
+            },
+        }
+    }
+
+    fn rem(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => Err(EngineError::DivisionByZero),
+            // i64::MIN % -1 is mathematically 0, so no mode can overflow.
+            (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a.wrapping_rem(b))),
+            (lhs, rhs) => match self.big(lhs)?.div_rem(&self.big(rhs)?) {
+                Some((_, remainder)) => self.big_result(mode, remainder),
+                None => Err(EngineError::DivisionByZero),
+            },
+        }
+    }
+
+    fn pow(&self, mode: Overflow, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        let base = self.big(lhs)?;
+        let exponent = match rhs {
+            Value::Int(b) => u64::try_from(b).map_err(|_| EngineError::NegativeExponent)?,
+            Value::BigInt(b) if b.is_negative() => return Err(EngineError::NegativeExponent),
+            Value::BigInt(_) => u64::MAX,
+            _ => return Err(EngineError::MismatchType),
+        };
+
+        // A lower bound on the bit length of the result, which lets huge
+        // powers fail (or saturate) before they are computed.
+        let bits = (base.bits().saturating_sub(1)).saturating_mul(exponent);
+        let negative = base.is_negative() && exponent % 2 == 1;
+
+        match mode {
+            Overflow::Checked if bits > MAX_BIGINT_BITS => Err(EngineError::Overflow),
+            Overflow::Checked => Ok(Value::from(base.pow(exponent))),
+            Overflow::Saturating if bits >= 64 && negative => Ok(Value::Int(i64::MIN)),
+            Overflow::Saturating if bits >= 64 => Ok(Value::Int(i64::MAX)),
+            Overflow::Saturating => Ok(Value::Int(base.pow(exponent).saturating_to_i64())),
+            Overflow::Wrapping => {
+                let mut base = base.wrapping_to_i64();
+                let mut exponent = exponent;
+                let mut result = 1i64;
+                while exponent > 0 {
+                    if exponent & 1 == 1 {
+                        result = result.wrapping_mul(base);
+                    }
+                    base = base.wrapping_mul(base);
+                    exponent >>= 1;
+                }
+                Ok(Value::Int(result))
+            }
+        }
+    }
+
+    fn neg(&self, mode: Overflow, value: Value) -> Result<Value, EngineError> {
+        match value {
+            Value::Int(a) => self.int_result(
+                mode,
+                a.checked_neg(),
+                a.wrapping_neg(),
+                a.saturating_neg(),
+                || -&BigInt::from(a),
+            ),
+            value => self.big_result(mode, -&self.big(value)?),
+        }
+    }
+
+    fn abs(&self, mode: Overflow, value: Value) -> Result<Value, EngineError> {
+        match value {
+            Value::Int(a) => self.int_result(
+                mode,
+                a.checked_abs(),
+                a.wrapping_abs(),
+                a.saturating_abs(),
+                || BigInt::from(a).abs(),
+            ),
+            value => self.big_result(mode, self.big(value)?.abs()),
+        }
+    }
+
+    fn ordering(&self, lhs: &Value, rhs: &Value) -> Option<Ordering> {
+        match (lhs, rhs) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+            (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+            (Value::Int(_) | Value::BigInt(_), Value::Int(_) | Value::BigInt(_)) => Some(
+                self.big(lhs.clone())
+                    .ok()?
+                    .cmp(&self.big(rhs.clone()).ok()?),
+            ),
+            _ => None,
         }
     }
 
     fn cmp(&self, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        match self.ordering(&lhs, &rhs) {
+            Some(Ordering::Greater) => Ok(Value::Int(-1)),
+            Some(Ordering::Equal) => Ok(Value::Int(0)),
+            Some(Ordering::Less) => Ok(Value::Int(1)),
+            None => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn compare(&self, command: &Command, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
+        let ordering = match self.ordering(&lhs, &rhs) {
+            Some(ordering) => ordering,
+            None => match command {
+                Command::Eq => return Ok(Value::Bool(lhs == rhs)),
+                Command::Ne => return Ok(Value::Bool(lhs != rhs)),
+                _ => return Err(EngineError::MismatchType),
+            },
+        };
+
+        let result = match command {
+            Command::Eq => ordering.is_eq(),
+            Command::Ne => ordering.is_ne(),
+            Command::Lt => ordering.is_lt(),
+            Command::Le => ordering.is_le(),
+            Command::Gt => ordering.is_gt(),
+            Command::Ge => ordering.is_ge(),
+            _ => unreachable!(),
+        };
+
+        Ok(Value::Bool(result))
+    }
+
+    fn logic(&self, command: &Command, lhs: Value, rhs: Value) -> Result<Value, EngineError> {
         match (lhs, rhs) {
-            (Value::Int(a), Value::Int(b)) => {
-                if a > b {
-                    return Ok(Value::Int(-1));
+            (Value::Bool(a), Value::Bool(b)) => match command {
+                Command::And => Ok(Value::Bool(a && b)),
+                Command::Or => Ok(Value::Bool(a || b)),
+                Command::Xor => Ok(Value::Bool(a ^ b)),
+                _ => Err(EngineError::MismatchType),
+            },
+            (Value::Int(a), Value::Int(b)) => match command {
+                Command::And => Ok(Value::Int(a & b)),
+                Command::Or => Ok(Value::Int(a | b)),
+                Command::Xor => Ok(Value::Int(a ^ b)),
+                Command::Shl | Command::Shr => {
+                    let amount = u32::try_from(b)
+                        .ok()
+                        .filter(|amount| *amount < i64::BITS)
+                        .ok_or(EngineError::InvalidShift(b))?;
+                    match command {
+                        Command::Shl => Ok(Value::Int(a << amount)),
+                        _ => Ok(Value::Int(a >> amount)),
+                    }
+                }
+                _ => unreachable!(),
+            },
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn not(&self, value: Value) -> Result<Value, EngineError> {
+        match value {
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            Value::Int(n) => Ok(Value::Int(!n)),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn error_value(&self, program: &Program, error: EngineError) -> ErrorValue {
+        match error {
+            EngineError::Thrown(e) => *e,
+            e => {
+                let location = program.location(self.pc);
+                ErrorValue {
+                    kind: e.kind().into(),
+                    message: e.to_string(),
+                    file: location.file,
+                    line: location.line,
+                }
+            }
+        }
+    }
+
+    fn can_catch(&self) -> bool {
+        !self.handlers.is_empty()
+            || self
+                .resumers
+                .iter()
+                .any(|(_, fiber)| !fiber.handlers.is_empty())
+    }
+
+    fn catch(&mut self, program: &Program, error: EngineError) -> Result<(), EngineError> {
+        if !error.is_catchable() || !self.can_catch() {
+            return Err(error);
+        }
+        let Some(handler) = self.handlers.pop() else {
+            // An error escaping a coroutine kills it and is rethrown in
+            // whoever resumed it, keeping the location where it happened.
+            let value = self.error_value(program, error);
+            self.suspend(Value::Nothing, true)?;
+            self.pop()?;
+            return self.catch(program, EngineError::Thrown(Box::new(value)));
+        };
+
+        let value = self.error_value(program, error);
+        self.split_stack(handler.stack);
+        let frames = handler.frames.min(self.pc_stack.len());
+        let pc_stack = self.pc_stack.split_off(frames);
+        let env_stack = self.env_stack.split_off(frames);
+        let env = std::mem::replace(&mut self.env, handler.env);
+        self.stack.push(Value::Error(Box::new(value)));
+        self.pc = handler.target;
+        self.log(Op::Catch {
+            handler,
+            pc_stack,
+            env_stack,
+            env,
+        });
+        Ok(())
+    }
+
+    fn frame_has_handler(&self) -> bool {
+        self.handlers
+            .last()
+            .is_some_and(|handler| handler.frames == self.pc_stack.len())
+    }
+
+    // Enters a script function, or runs a native one to completion. Returns
+    // whether control moved to the callee.
+    fn call(
+        &mut self,
+        program: &Program,
+        name: &str,
+        env: Option<usize>,
+        tail: bool,
+    ) -> Result<bool, EngineError> {
+        if let Some(&address) = program.functions.get(name) {
+            if !tail {
+                if self.pc_stack.len() >= MAX_CALL_DEPTH {
+                    return Err(EngineError::StackOverflow);
+                }
+                self.pc_stack.push(self.pc + 1);
+                self.env_stack.push(self.env);
+                self.log(Op::Call);
+            } else {
+                self.log(Op::Env(self.env));
+            }
+            self.env = env;
+            self.pc = address;
+            return Ok(true);
+        }
+
+        let native = match self.natives.get(name) {
+            Some(native) => native.clone(),
+            None => return Err(EngineError::UnknownSymbol(name.into())),
+        };
+        let base = self
+            .stack
+            .len()
+            .checked_sub(native.arity)
+            .ok_or(EngineError::EmptyStack)?;
+        let args = self.split_stack(base);
+        let result = match &mut self.journal {
+            Some(journal @ Journal::Replay { .. }) => {
+                let recorded = journal.replay(name, &args)?;
+                if native.output {
+                    (native.function)(&args)?;
                 }
-                if a == b {
-                    return Ok(Value::Int(0));
+                recorded.map_err(|e| EngineError::Thrown(Box::new(e)))
+            }
+            _ => (native.function)(&args),
+        };
+        let result = match (&self.journal, result) {
+            (Some(Journal::Record(_)), result) => {
+                let result = match result {
+                    Ok(value) => Ok(value),
+                    Err(e) if e.is_catchable() => Err(self.error_value(program, e)),
+                    Err(e) => return Err(e),
+                };
+                if let Some(Journal::Record(calls)) = &mut self.journal {
+                    calls.push(Call {
+                        name: name.into(),
+                        args,
+                        result: result.clone(),
+                    });
                 }
-                Ok(Value::Int(1))
+                result.map_err(|e| EngineError::Thrown(Box::new(e)))?
+            }
+            (_, result) => result?,
+        };
+        // A native call cannot be cut short, but the deadline is enforced
+        // before the next instruction, once its result is on the stack.
+        self.overdue_check = true;
+        match result {
+            Value::Nothing => {}
+            result => self.stack.push(result),
+        }
+        Ok(false)
+    }
+
+    fn function(&mut self) -> Result<(String, Option<usize>), EngineError> {
+        match self.pop()? {
+            Value::Function(name) => Ok((name, None)),
+            Value::Closure(handle) => {
+                Ok((self.heap.closure(handle).function.clone(), Some(handle)))
             }
             _ => Err(EngineError::MismatchType),
         }
     }
 
+    fn closure(
+        &mut self,
+        program: &Program,
+        function: &str,
+        count: usize,
+    ) -> Result<Value, EngineError> {
+        if !program.functions.contains_key(function) {
+            return Err(EngineError::UnknownSymbol(function.into()));
+        }
+        let base = self
+            .stack
+            .len()
+            .checked_sub(count)
+            .ok_or(EngineError::EmptyStack)?;
+
+        let captures = self.split_stack(base);
+        let handle = self.alloc(Object::Closure(Closure {
+            function: function.into(),
+            captures,
+        }));
+        Ok(Value::Closure(handle))
+    }
+
+    fn alloc(&mut self, object: Object) -> usize {
+        if self.heap.should_collect() {
+            let mut handles: Vec<usize> = self
+                .env_stack
+                .iter()
+                .chain([&self.env])
+                .flatten()
+                .copied()
+                .collect();
+            object.trace(&mut handles);
+            let resumers = self
+                .tasks
+                .iter()
+                .flat_map(|task| &task.resumers)
+                .chain(&self.resumers);
+            for (handle, fiber) in resumers {
+                handles.push(*handle);
+                fiber.trace(&mut handles);
+            }
+            for task in &self.tasks {
+                task.fiber.trace(&mut handles);
+            }
+            let results = self.tasks.iter().filter_map(|task| task.result.as_ref());
+            let collected = self.heap.collect(
+                self.stack.iter().chain(self.vars.values()).chain(results),
+                &handles,
+            );
+            self.log(Op::Collect(collected));
+        }
+
+        let slots = self.heap.slots();
+        let handle = self.heap.alloc(object);
+        self.log(Op::Alloc {
+            handle,
+            reused: handle < slots,
+        });
+        handle
+    }
+
+    fn switch(&mut self, fiber: &mut Fiber) {
+        if let Some(trail) = &mut self.trail {
+            trail.cut(fiber.stack.len(), fiber.pc);
+        }
+        std::mem::swap(&mut self.stack, &mut fiber.stack);
+        std::mem::swap(&mut self.pc, &mut fiber.pc);
+        std::mem::swap(&mut self.pc_stack, &mut fiber.pc_stack);
+        std::mem::swap(&mut self.env, &mut fiber.env);
+        std::mem::swap(&mut self.env_stack, &mut fiber.env_stack);
+        std::mem::swap(&mut self.handlers, &mut fiber.handlers);
+    }
+
+    fn coroutine(&mut self, program: &Program) -> Result<Value, EngineError> {
+        let (name, env) = self.function()?;
+        let pc = match program.functions.get(&name) {
+            Some(&pc) => pc,
+            None => return Err(EngineError::UnknownSymbol(name)),
+        };
+
+        let handle = self.alloc(Object::Coroutine(Coroutine {
+            status: Status::Suspended,
+            fiber: Fiber {
+                pc,
+                env,
+                ..Default::default()
+            },
+        }));
+        Ok(Value::Coroutine(handle))
+    }
+
+    fn spawn(&mut self, program: &Program) -> Result<Value, EngineError> {
+        let (name, env) = self.function()?;
+        let argument = self.pop()?;
+        let pc = match program.functions.get(&name) {
+            Some(&pc) => pc,
+            None => return Err(EngineError::UnknownSymbol(name)),
+        };
+
+        self.tasks.push(Task {
+            fiber: Fiber {
+                stack: vec![argument],
+                pc,
+                env,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        self.log(Op::Spawn);
+        Ok(Value::Task(self.tasks.len() - 1))
+    }
+
+    fn channel_handle(&self, depth: usize) -> Result<usize, EngineError> {
+        match self.peek(depth)? {
+            Value::Channel(handle) => Ok(*handle),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn ready(&self, wait: Wait) -> bool {
+        match wait {
+            Wait::Join(id) => self.tasks[id].result.is_some(),
+            Wait::Send(handle) => !self.heap.channel(handle).is_full(),
+            Wait::Recv(handle) => !self.heap.channel(handle).queue.is_empty(),
+        }
+    }
+
+    fn runnable(&self, id: usize) -> bool {
+        let task = &self.tasks[id];
+        task.result.is_none() && task.wait.is_none_or(|wait| self.ready(wait))
+    }
+
+    // Moves on to the next task that can make progress, round-robin. A
+    // blocked or finished task must give way; if nobody else can run that
+    // is a deadlock.
+    fn reschedule(&mut self, must: bool) -> Result<(), EngineError> {
+        let count = self.tasks.len();
+        let next = (1..count)
+            .map(|offset| (self.current + offset) % count)
+            .find(|&id| self.runnable(id));
+
+        match next {
+            Some(next) => {
+                let mut task = std::mem::take(&mut self.tasks[next]);
+                self.switch(&mut task.fiber);
+                std::mem::swap(&mut self.resumers, &mut task.resumers);
+
+                let previous = &mut self.tasks[self.current];
+                previous.fiber = task.fiber;
+                previous.resumers = task.resumers;
+                previous.wait = self.wait.take();
+                self.log(Op::Reschedule {
+                    from: self.current,
+                    wait: task.wait,
+                });
+                self.current = next;
+                self.ticks = 0;
+                Ok(())
+            }
+            None if must => Err(EngineError::Deadlock),
+            None => Ok(()),
+        }
+    }
+
+    /// The call stack of the running task, innermost frame first.
+    pub fn backtrace(&self, program: &Program) -> Vec<Frame> {
+        let calls = self.pc_stack.iter().rev().map(|ret| ret.saturating_sub(1));
+
+        std::iter::once(self.pc)
+            .chain(calls)
+            .map(|pc| {
+                let location = program.location(pc);
+                Frame {
+                    pc,
+                    function: program.function_at(pc).into(),
+                    file: location.file,
+                    line: location.line,
+                }
+            })
+            .collect()
+    }
+
+    fn tick(&mut self) -> Result<(), EngineError> {
+        self.ticks += 1;
+        if self.ticks >= TIME_SLICE && self.tasks.len() > 1 {
+            self.reschedule(false)?;
+        }
+        Ok(())
+    }
+
+    fn coroutine_handle(&mut self) -> Result<usize, EngineError> {
+        match self.pop()? {
+            Value::Coroutine(handle) => Ok(handle),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn resume_coroutine(&mut self) -> Result<(), EngineError> {
+        let handle = self.coroutine_handle()?;
+        let value = self.pop()?;
+
+        let coroutine = self.heap.coroutine_mut(handle);
+        if coroutine.status != Status::Suspended {
+            return Err(EngineError::NotResumable(coroutine.status.name()));
+        }
+        coroutine.status = Status::Running;
+        let mut fiber = std::mem::take(&mut coroutine.fiber);
+
+        self.pc += 1;
+        self.switch(&mut fiber);
+        self.resumers.push((handle, fiber));
+        self.stack.push(value);
+        self.log(Op::Resume(handle));
+        Ok(())
+    }
+
+    // Hands `value` back to whoever resumed the running coroutine, leaving it
+    // suspended, or dead when `finished`.
+    fn suspend(&mut self, value: Value, finished: bool) -> Result<(), EngineError> {
+        let Some((handle, mut fiber)) = self.resumers.pop() else {
+            return Err(EngineError::NotInCoroutine);
+        };
+
+        self.switch(&mut fiber);
+        let coroutine = self.heap.coroutine_mut(handle);
+        let fiber = if finished {
+            coroutine.status = Status::Dead;
+            Some(fiber)
+        } else {
+            coroutine.status = Status::Suspended;
+            coroutine.fiber = fiber;
+            None
+        };
+        self.stack.push(value);
+        self.log(Op::Suspend { handle, fiber });
+        Ok(())
+    }
+
+    fn capture(&mut self, slot: usize) -> Result<&mut Value, EngineError> {
+        self.env
+            .and_then(|handle| self.heap.closure_mut(handle).captures.get_mut(slot))
+            .ok_or(EngineError::NoCapture(slot))
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.heap.live()
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// The operand stack of the running task, top last.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn vars(&self) -> &HashMap<String, Value> {
+        &self.vars
+    }
+
+    /// How many calls the running task is nested in.
+    pub fn call_depth(&self) -> usize {
+        self.pc_stack.len()
+    }
+
+    /// The captures of the closure running `frames` calls below the
+    /// innermost one.
+    pub fn captures(&self, frames: usize) -> &[Value] {
+        let env = match frames {
+            0 => self.env,
+            n => self
+                .env_stack
+                .len()
+                .checked_sub(n)
+                .and_then(|at| self.env_stack[at]),
+        };
+        env.map_or(&[], |handle| &self.heap.closure(handle).captures)
+    }
+
+    // Returns the program result when returning from `main`.
+    fn ret(&mut self) -> Option<Value> {
+        let pc = match self.pc_stack.pop() {
+            Some(pc) => pc,
+            None if self.resumers.is_empty() => return Some(self.result()),
+            None => {
+                let result = self.result();
+                self.suspend(result, true).ok();
+                return None;
+            }
+        };
+
+        // Handlers are ordered by frame, so those of the returning frame are
+        // at the end.
+        let frames = self.pc_stack.len();
+        let keep = self
+            .handlers
+            .partition_point(|handler| handler.frames <= frames);
+        let handlers = self.handlers.split_off(keep);
+        let env = std::mem::replace(&mut self.env, self.env_stack.pop().flatten());
+        self.pc = pc;
+        self.log(Op::Ret { pc, env, handlers });
+        None
+    }
+
+    fn caught(&mut self) -> Result<ErrorValue, EngineError> {
+        match self.pop()? {
+            Value::Error(e) => Ok(*e),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn truth(&self, value: Value) -> Result<bool, EngineError> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(EngineError::MismatchType),
+        }
+    }
+
+    fn check_interrupt(&self, program: &Program) -> Result<(), EngineError> {
+        if self.interrupt.is_interrupted() {
+            return Err(EngineError::Interrupted {
+                pc: self.pc,
+                backtrace: self.backtrace(program),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_deadline(&self, program: &Program) -> Result<(), EngineError> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(EngineError::Timeout {
+                pc: self.pc,
+                backtrace: self.backtrace(program),
+            }),
+            _ => Ok(()),
+        }
+    }
+
     pub fn evaluate(&mut self, program: &Program) -> Result<Value, EngineError> {
-        self.pc = program.functions["main"];
-        let mut output = Ok(Value::Nothing);
-        let mut update_pc: bool;
+        self.start(program)?;
+        self.resume(program)
+    }
 
+    /// Continues from wherever evaluation stopped, typically after
+    /// `restore` or an interrupt.
+    pub fn resume(&mut self, program: &Program) -> Result<Value, EngineError> {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let result = self.run(program)?;
+        self.finish(program, &result)?;
+        Ok(result)
+    }
+
+    /// Like `evaluate`, but fails with `EngineError::Timeout` once `deadline`
+    /// has passed.
+    pub fn evaluate_until(
+        &mut self,
+        program: &Program,
+        deadline: Instant,
+    ) -> Result<Value, EngineError> {
+        self.start(program)?;
+        self.deadline = Some(deadline);
+        let result = self.run(program)?;
+        self.finish(program, &result)?;
+        Ok(result)
+    }
+
+    fn run(&mut self, program: &Program) -> Result<Value, EngineError> {
         loop {
-            update_pc = true;
-            if self.pc >= program.commands.len() {
-                break;
+            if let Some(value) = self.advance(program)? {
+                // `execute` only looks every `INTERRUPT_INTERVAL` instructions.
+                self.check_interrupt(program)?;
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Sets up a fresh evaluation of `program` without running any of it,
+    /// for callers that drive it with `advance`.
+    pub fn start(&mut self, program: &Program) -> Result<(), EngineError> {
+        self.pc = match program.functions.get("main") {
+            Some(&pc) => pc,
+            None => return Err(EngineError::UnknownSymbol("main".into())),
+        };
+        self.load_persistent(program)
+    }
+
+    /// Runs one instruction of the current task and lets the scheduler and
+    /// any `try` handler react to it. Returns the result once the main task
+    /// is done, or `EngineError::Paused` if a watchpoint asked to stop.
+    pub fn advance(&mut self, program: &Program) -> Result<Option<Value>, EngineError> {
+        if self.watches.is_empty() {
+            return self.execute(program);
+        }
+
+        let pc = self.pc;
+        let (current, resumers) = (self.current, self.resumers.len());
+        let depth = self.stack.len();
+        let written = self.written(program.commands.get(pc)).cloned();
+        let old = written
+            .as_ref()
+            .and_then(|name| self.vars.get(name).cloned());
+
+        let result = self.execute(program)?;
+
+        let mut events = vec![];
+        if let Some((name, new)) =
+            written.and_then(|name| Some((name.clone(), self.vars.get(&name)?.clone())))
+        {
+            events.push(Event::Write { name, old, new });
+        }
+        // After a task switch, `resume` or `yield`, the stack belongs to
+        // someone else and its depth says nothing about this one.
+        let switched = self.current != current || self.resumers.len() != resumers;
+        if !switched && self.stack.len() != depth {
+            events.push(Event::Depth {
+                from: depth,
+                to: self.stack.len(),
+            });
+        }
+
+        let mut paused = None;
+        for watchpoint in &mut self.watches {
+            if watchpoint.notify(pc, &events) {
+                paused.get_or_insert(watchpoint.id);
             }
+        }
+        match (result, paused) {
+            (None, Some(watch)) => Err(EngineError::Paused { pc: self.pc, watch }),
+            (result, _) => Ok(result),
+        }
+    }
+
+    // The variable `command` will write, if it runs successfully.
+    fn written<'c>(&self, command: Option<&'c Command>) -> Option<&'c String> {
+        match command {
+            Some(Command::SetVar(name, _)) => Some(name),
+            Some(Command::Store(name)) if !self.stack.is_empty() => Some(name),
+            _ => None,
+        }
+    }
+
+    fn execute(&mut self, program: &Program) -> Result<Option<Value>, EngineError> {
+        self.executed += 1;
+        if self.executed.is_multiple_of(INTERRUPT_INTERVAL) {
+            self.check_interrupt(program)?;
+            self.check_deadline(program)?;
+        } else if std::mem::take(&mut self.overdue_check) {
+            self.check_deadline(program)?;
+        }
+
+        match self.step(program) {
+            Ok(Step::Continue) => self.tick()?,
+            Ok(Step::Blocked) => self.reschedule(true)?,
+            Ok(Step::Done(value)) if self.current == MAIN_TASK => return Ok(Some(value)),
+            Ok(Step::Done(value)) => {
+                self.tasks[self.current].result = Some(value);
+                self.log(Op::Finished(self.current));
+                self.reschedule(true)?;
+            }
+            Err(e) => self.catch(program, e)?,
+        }
+        Ok(None)
+    }
+
+    /// Like `advance`, also returning what `undo` needs to take it back.
+    /// Only what the instruction changed is kept, not a copy of the machine.
+    /// Native functions are not undone; they run again when execution
+    /// passes them a second time.
+    pub fn advance_undoable(
+        &mut self,
+        program: &Program,
+    ) -> (Undo, Result<Option<Value>, EngineError>) {
+        let pc = self.pc;
+        let ticks = self.ticks;
+        let written = self.written(program.commands.get(pc)).cloned();
+        let old = written
+            .as_ref()
+            .and_then(|name| self.vars.get(name).cloned());
 
-            let command = &program.commands[self.pc];
+        self.trail = Some(Trail {
+            floor: self.stack.len(),
+            pc,
+            ..Default::default()
+        });
+        let result = self.advance(program);
+        let mut trail = self.trail.take().unwrap_or_default();
+        trail.cut(0, 0);
 
-            match command {
-                Command::SetVar(name, value) => {
-                    self.vars.insert(name.into(), value.clone());
+        let written = written.and_then(|name| {
+            let value = self.vars.get(&name)?.clone();
+            Some((name, value))
+        });
+        let undo = Undo {
+            pc,
+            ticks,
+            written,
+            old,
+            ops: trail.ops,
+        };
+        (undo, result)
+    }
+
+    /// Puts the machine back the way it was before the `advance_undoable`
+    /// that returned `undo`. Undos must be applied newest first.
+    pub fn undo(&mut self, undo: Undo) {
+        for op in undo.ops.into_iter().rev() {
+            self.revert(op);
+        }
+        self.pc = undo.pc;
+        self.ticks = undo.ticks;
+        if let Some((name, _)) = undo.written {
+            match undo.old {
+                Some(value) => self.vars.insert(name, value),
+                None => self.vars.remove(&name),
+            };
+        }
+    }
+
+    fn revert(&mut self, op: Op) {
+        match op {
+            Op::Stack { floor, sunk, pc } => {
+                self.pc = pc;
+                self.stack.truncate(floor);
+                self.stack.extend(sunk.into_iter().rev());
+            }
+            Op::Call => {
+                self.pc_stack.pop();
+                self.env = self.env_stack.pop().flatten();
+            }
+            Op::Env(env) => self.env = env,
+            Op::Ret { pc, env, handlers } => {
+                self.pc_stack.push(pc);
+                self.env_stack.push(self.env);
+                self.env = env;
+                self.handlers.extend(handlers);
+            }
+            Op::Try => {
+                self.handlers.pop();
+            }
+            Op::EndTry(handler) => self.handlers.push(handler),
+            Op::Catch {
+                handler,
+                pc_stack,
+                env_stack,
+                env,
+            } => {
+                self.pc_stack.extend(pc_stack);
+                self.env_stack.extend(env_stack);
+                self.env = env;
+                self.handlers.push(handler);
+            }
+            Op::Resume(handle) => {
+                if let Some((_, mut fiber)) = self.resumers.pop() {
+                    self.switch(&mut fiber);
+                    let coroutine = self.heap.coroutine_mut(handle);
+                    coroutine.status = Status::Suspended;
+                    coroutine.fiber = fiber;
                 }
-                Command::GetVar(name) => match self.vars.get(name) {
-                    Some(value) => output = Ok(value.clone()),
-                    None => return Err(EngineError::MissingVariable(name.into())),
-                },
-                Command::Push(value) => {
-                    self.push(value.clone())?;
+            }
+            Op::Suspend { handle, fiber } => {
+                let coroutine = self.heap.coroutine_mut(handle);
+                coroutine.status = Status::Running;
+                let mut fiber = fiber.unwrap_or_else(|| std::mem::take(&mut coroutine.fiber));
+                self.switch(&mut fiber);
+                self.resumers.push((handle, fiber));
+            }
+            Op::Reschedule { from, wait } => {
+                let previous = &mut self.tasks[from];
+                let mut fiber = std::mem::take(&mut previous.fiber);
+                let mut resumers = std::mem::take(&mut previous.resumers);
+                let previous_wait = previous.wait.take();
+                self.switch(&mut fiber);
+                std::mem::swap(&mut self.resumers, &mut resumers);
+                self.wait = previous_wait;
+                self.tasks[self.current] = Task {
+                    fiber,
+                    resumers,
+                    wait,
+                    result: None,
+                };
+                self.current = from;
+            }
+            Op::Spawn => {
+                self.tasks.pop();
+            }
+            Op::Wait(wait) => self.wait = wait,
+            Op::Finished(id) => self.tasks[id].result = None,
+            Op::Alloc { handle, reused } => self.heap.unalloc(handle, reused),
+            Op::Collect(collected) => self.heap.uncollect(collected),
+            Op::Capture { handle, slot, old } => {
+                self.heap.closure_mut(handle).captures[slot] = old;
+            }
+            Op::Sent(handle) => {
+                self.heap.channel_mut(handle).queue.pop_back();
+            }
+            Op::Received(handle, value) => {
+                self.heap.channel_mut(handle).queue.push_front(value);
+            }
+        }
+    }
+
+    fn result(&self) -> Value {
+        self.stack.last().cloned().unwrap_or(Value::Nothing)
+    }
+
+    pub fn step(&mut self, program: &Program) -> Result<Step, EngineError> {
+        let mut update_pc = true;
+        if self.pc >= program.commands.len() {
+            return Ok(Step::Done(self.result()));
+        }
+
+        let command = &program.commands[self.pc];
+
+        match command {
+            Command::SetVar(name, value) => {
+                self.vars.insert(name.into(), value.clone());
+            }
+            Command::GetVar(name) => match self.vars.get(name) {
+                Some(value) => self.stack.push(value.clone()),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::Store(name) => {
+                let value = self.pop()?;
+                self.vars.insert(name.into(), value);
+            }
+            Command::Load(name) => match self.vars.get(name) {
+                Some(value) => self.stack.push(value.clone()),
+                None => return Err(EngineError::MissingVariable(name.into())),
+            },
+            Command::Push(value) => {
+                self.push(value.clone())?;
+            }
+            Command::Pop => {
+                self.pop()?;
+            }
+            Command::Add(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.add(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Mul(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.mul(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Sub(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.sub(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Div(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.div(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Mod(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.rem(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Pow(mode) => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
+
+                let result = self.pow(*mode, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Neg(mode) => {
+                let value = self.pop()?;
+
+                let result = self.neg(*mode, value)?;
+                self.stack.push(result);
+            }
+            Command::Abs(mode) => {
+                let value = self.pop()?;
+
+                let result = self.abs(*mode, value)?;
+                self.stack.push(result);
+            }
+            Command::FuncCall(_) | Command::ICall => {
+                let (name, env) = match command {
+                    Command::FuncCall(name) => (name.clone(), None),
+                    _ => self.function()?,
+                };
+                // A call directly followed by `ret` reuses the current frame,
+                // unless a `try` in this frame still has to see its errors.
+                let tail = matches!(program.commands.get(self.pc + 1), Some(Command::Ret))
+                    && !self.frame_has_handler();
+                if self.call(program, &name, env, tail)? {
+                    update_pc = false;
                 }
-                Command::Pop => {
-                    output = self.pop();
+            }
+            Command::TailCall(name) => {
+                if self.frame_has_handler() {
+                    return Err(EngineError::TailCall(name.into()));
                 }
-                Command::Add => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
-
-                    let result = self.add(lhs, rhs)?;
-                    self.stack.push(result);
+                if !self.call(program, name, None, true)? {
+                    if let Some(result) = self.ret() {
+                        return Ok(Step::Done(result));
+                    }
                 }
-                Command::Mul => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
-
-                    let result = self.mul(lhs, rhs)?;
-                    self.stack.push(result);
+                update_pc = false;
+            }
+            Command::PushFn(name) => {
+                if !program.functions.contains_key(name) && !self.natives.contains_key(name) {
+                    return Err(EngineError::UnknownSymbol(name.into()));
                 }
-                Command::Sub => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
-
-                    let result = self.sub(lhs, rhs)?;
-                    self.stack.push(result);
+                self.push(Value::Function(name.clone()))?;
+            }
+            Command::Closure(function, count) => {
+                let closure = self.closure(program, function, *count)?;
+                self.push(closure)?;
+            }
+            Command::GetCap(slot) => {
+                let value = self.capture(*slot)?.clone();
+                self.push(value)?;
+            }
+            Command::SetCap(slot) => {
+                let value = self.pop()?;
+                let old = std::mem::replace(self.capture(*slot)?, value);
+                if let Some(handle) = self.env {
+                    self.log(Op::Capture {
+                        handle,
+                        slot: *slot,
+                        old,
+                    });
                 }
-                Command::Div => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
-
-                    let result = self.div(lhs, rhs)?;
-                    self.stack.push(result);
+            }
+            Command::Coroutine => {
+                let coroutine = self.coroutine(program)?;
+                self.push(coroutine)?;
+            }
+            Command::Resume => {
+                self.resume_coroutine()?;
+                update_pc = false;
+            }
+            Command::Yield => {
+                let value = self.pop()?;
+                self.pc += 1;
+                if let Err(e) = self.suspend(value, false) {
+                    self.pc -= 1;
+                    return Err(e);
                 }
-                Command::FuncCall(name) => {
-                    if name == "print" {
-                        let value = self.pop()?;
-                        println!("{}", value);
-                    } else {
-                        self.pc_stack.push(self.pc + 1);
-                        self.pc = program.functions[name];
-                        update_pc = false;
+                update_pc = false;
+            }
+            Command::Status => {
+                let handle = self.coroutine_handle()?;
+                let status = self.heap.coroutine_mut(handle).status;
+                self.push(Value::String(status.name().into()))?;
+            }
+            Command::Spawn => {
+                let task = self.spawn(program)?;
+                self.push(task)?;
+            }
+            Command::Join => {
+                let id = match self.peek(0)? {
+                    Value::Task(id) => *id,
+                    _ => return Err(EngineError::MismatchType),
+                };
+                match &self.tasks[id].result {
+                    Some(result) => {
+                        let result = result.clone();
+                        self.pop()?;
+                        self.push(result)?;
                     }
+                    None => {
+                        self.log(Op::Wait(self.wait));
+                        self.wait = Some(Wait::Join(id));
+                        return Ok(Step::Blocked);
+                    }
+                }
+            }
+            Command::Channel => match self.pop()? {
+                Value::Int(capacity) if capacity > 0 => {
+                    let handle = self.alloc(Object::Channel(Channel {
+                        capacity: capacity as usize,
+                        queue: Default::default(),
+                    }));
+                    self.push(Value::Channel(handle))?;
+                }
+                Value::Int(capacity) => return Err(EngineError::InvalidCapacity(capacity)),
+                _ => return Err(EngineError::MismatchType),
+            },
+            Command::Send => {
+                let handle = self.channel_handle(0)?;
+                self.peek(1)?;
+                if self.heap.channel(handle).is_full() {
+                    self.log(Op::Wait(self.wait));
+                    self.wait = Some(Wait::Send(handle));
+                    return Ok(Step::Blocked);
                 }
-                Command::Ret => {
-                    if let Some(pc) = self.pc_stack.pop() {
-                        self.pc = pc;
-                        update_pc = false;
-                    } else {
-                        return Err(EngineError::EmptyStack);
+                self.pop()?;
+                let value = self.pop()?;
+                self.heap.channel_mut(handle).queue.push_back(value);
+                self.log(Op::Sent(handle));
+            }
+            Command::Recv => {
+                let handle = self.channel_handle(0)?;
+                match self.heap.channel_mut(handle).queue.pop_front() {
+                    Some(value) => {
+                        if self.trail.is_some() {
+                            self.log(Op::Received(handle, value.clone()));
+                        }
+                        self.pop()?;
+                        self.push(value)?;
+                    }
+                    None => {
+                        self.log(Op::Wait(self.wait));
+                        self.wait = Some(Wait::Recv(handle));
+                        return Ok(Step::Blocked);
                     }
                 }
-                Command::End => {
-                    break;
+            }
+            Command::Ret => {
+                if let Some(result) = self.ret() {
+                    return Ok(Step::Done(result));
                 }
-                Command::Cmp => {
-                    let lhs = self.pop()?;
-                    let rhs = self.pop()?;
+                update_pc = false;
+            }
+            Command::End | Command::Halt => {
+                return Ok(Step::Done(self.result()));
+            }
+            Command::Exit(Some(code)) => {
+                return Ok(Step::Done(Value::Int(*code)));
+            }
+            Command::Exit(None) => {
+                return match self.pop()? {
+                    Value::Int(code) => Ok(Step::Done(Value::Int(code))),
+                    _ => Err(EngineError::MismatchType),
+                };
+            }
+            Command::Cmp => {
+                let lhs = self.pop()?;
+                let rhs = self.pop()?;
 
-                    let result = self.cmp(lhs, rhs)?;
-                    self.stack.push(result);
+                let result = self.cmp(lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Dup => {
+                let value = self.peek(0)?.clone();
+                self.push(value)?;
+            }
+            Command::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+            }
+            Command::Over => {
+                let value = self.peek(1)?.clone();
+                self.push(value)?;
+            }
+            Command::Rot => {
+                let c = self.pop()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(c)?;
+                self.push(a)?;
+            }
+            Command::Drop => {
+                self.pop()?;
+            }
+            Command::Pick(n) => {
+                let value = self.peek(*n)?.clone();
+                self.push(value)?;
+            }
+            Command::Eq | Command::Ne | Command::Lt | Command::Le | Command::Gt | Command::Ge => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.compare(command, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::And | Command::Or | Command::Xor | Command::Shl | Command::Shr => {
+                let rhs = self.pop()?;
+                let lhs = self.pop()?;
+
+                let result = self.logic(command, lhs, rhs)?;
+                self.stack.push(result);
+            }
+            Command::Not => {
+                let value = self.pop()?;
+
+                let result = self.not(value)?;
+                self.stack.push(result);
+            }
+            Command::Jmp(label) => {
+                self.pc = program.labels[label];
+                update_pc = false;
+            }
+            Command::Jt(label) => {
+                let value = self.pop()?;
+                if self.truth(value)? {
+                    self.pc = program.labels[label];
+                    update_pc = false;
                 }
-                Command::Jn(label) => {
-                    let value = self.pop()?;
-                    match value {
-                        Value::Int(x) => {
-                            if x < 0 {
-                                self.pc = program.labels[label];
-                                update_pc = false;
-                            }
+            }
+            Command::Jf(label) => {
+                let value = self.pop()?;
+                if !self.truth(value)? {
+                    self.pc = program.labels[label];
+                    update_pc = false;
+                }
+            }
+            Command::Try(label) => {
+                self.handlers.push(Handler {
+                    target: program.labels[label],
+                    stack: self.stack.len(),
+                    frames: self.pc_stack.len(),
+                    env: self.env,
+                });
+                self.log(Op::Try);
+            }
+            Command::EndTry => {
+                let frames = self.pc_stack.len();
+                match self.handlers.last() {
+                    Some(handler) if handler.frames == frames => {
+                        if let Some(handler) = self.handlers.pop() {
+                            self.log(Op::EndTry(handler));
                         }
-                        Value::Nothing => return Err(EngineError::EmptyStack),
-                        Value::String(_) => return Err(EngineError::EmptyStack),
-                    }
-                }
-                Command::Jp(label) => {
-                    let value = self.pop()?;
-                    match value {
-                        Value::Int(x) => {
-                            if x > 0 {
-                                self.pc = program.labels[label];
-                                update_pc = false;
-                            }
+                    }
+                    _ => return Err(EngineError::NoHandler),
+                }
+            }
+            Command::Throw => {
+                let error = match self.pop()? {
+                    Value::Error(e) => *e,
+                    value => {
+                        let location = program.location(self.pc);
+                        ErrorValue {
+                            kind: "thrown".into(),
+                            message: match value {
+                                Value::String(s) => s,
+                                value => value.to_string(),
+                            },
+                            file: location.file,
+                            line: location.line,
                         }
-                        Value::Nothing => return Err(EngineError::EmptyStack),
-                        Value::String(_) => return Err(EngineError::EmptyStack),
-                    }
-                }
-                Command::Jz(label) => {
-                    let value = self.pop()?;
-                    match value {
-                        Value::Int(x) => {
-                            if x == 0 {
-                                self.pc = program.labels[label];
-                                update_pc = false;
-                            }
+                    }
+                };
+                return Err(EngineError::Thrown(Box::new(error)));
+            }
+            Command::ErrKind => {
+                let error = self.caught()?;
+                self.push(Value::String(error.kind))?;
+            }
+            Command::ErrMsg => {
+                let error = self.caught()?;
+                self.push(Value::String(error.message))?;
+            }
+            Command::ErrLine => {
+                let error = self.caught()?;
+                self.push(Value::Int(error.line as i64))?;
+            }
+            Command::Jn(label) => {
+                let value = self.pop()?;
+                match value {
+                    Value::Int(x) => {
+                        if x < 0 {
+                            self.pc = program.labels[label];
+                            update_pc = false;
                         }
-                        Value::Nothing => return Err(EngineError::EmptyStack),
-                        Value::String(_) => return Err(EngineError::EmptyStack),
                     }
+                    Value::BigInt(x) => {
+                        if x.is_negative() {
+                            self.pc = program.labels[label];
+                            update_pc = false;
+                        }
+                    }
+                    Value::Nothing => return Err(EngineError::EmptyStack),
+                    Value::String(_) => return Err(EngineError::EmptyStack),
+                    _ => return Err(EngineError::MismatchType),
                 }
             }
-
-            if update_pc {
-                self.pc += 1;
+            Command::Jp(label) => {
+                let value = self.pop()?;
+                match value {
+                    Value::Int(x) => {
+                        if x > 0 {
+                            self.pc = program.labels[label];
+                            update_pc = false;
+                        }
+                    }
+                    Value::BigInt(x) => {
+                        if !x.is_negative() && !x.is_zero() {
+                            self.pc = program.labels[label];
+                            update_pc = false;
+                        }
+                    }
+                    Value::Nothing => return Err(EngineError::EmptyStack),
+                    Value::String(_) => return Err(EngineError::EmptyStack),
+                    _ => return Err(EngineError::MismatchType),
+                }
+            }
+            Command::Jz(label) => {
+                let value = self.pop()?;
+                match value {
+                    Value::Int(x) => {
+                        if x == 0 {
+                            self.pc = program.labels[label];
+                            update_pc = false;
+                        }
+                    }
+                    Value::BigInt(x) => {
+                        if x.is_zero() {
+                            self.pc = program.labels[label];
+                            update_pc = false;
+                        }
+                    }
+                    Value::Nothing => return Err(EngineError::EmptyStack),
+                    Value::String(_) => return Err(EngineError::EmptyStack),
+                    _ => return Err(EngineError::MismatchType),
+                }
             }
         }
 
-        output
+        if update_pc {
+            self.pc += 1;
+        }
+
+        Ok(Step::Continue)
     }
 }
 
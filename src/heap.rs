@@ -0,0 +1,337 @@
+use std::collections::VecDeque;
+
+use crate::command::{EngineError, Value};
+use crate::eval::Fiber;
+use crate::snapshot::{invalid, Reader, Writer};
+
+const MIN_THRESHOLD: usize = 256;
+
+#[derive(Debug)]
+pub struct Closure {
+    pub function: String,
+    pub captures: Vec<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Suspended,
+    Running,
+    Dead,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Dead => "dead",
+        }
+    }
+}
+
+/// A coroutine's saved execution state. While it runs, its fiber is swapped
+/// into the evaluator and the one kept here is empty.
+#[derive(Debug)]
+pub struct Coroutine {
+    pub status: Status,
+    pub fiber: Fiber,
+}
+
+/// A bounded FIFO queue between tasks.
+#[derive(Debug)]
+pub struct Channel {
+    pub capacity: usize,
+    pub queue: VecDeque<Value>,
+}
+
+impl Channel {
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+pub enum Object {
+    Closure(Closure),
+    Coroutine(Coroutine),
+    Channel(Channel),
+}
+
+impl Object {
+    pub fn trace(&self, pending: &mut Vec<usize>) {
+        match self {
+            Object::Closure(closure) => {
+                pending.extend(closure.captures.iter().filter_map(handle_of));
+            }
+            Object::Coroutine(coroutine) => coroutine.fiber.trace(pending),
+            Object::Channel(channel) => {
+                pending.extend(channel.queue.iter().filter_map(handle_of));
+            }
+        }
+    }
+}
+
+/// What a collection freed, kept so it can be taken back.
+#[derive(Debug)]
+pub struct Collected {
+    freed: Vec<(usize, Object)>,
+    threshold: usize,
+}
+
+/// Closures and coroutines live here and are referred to by handles, so
+/// every copy of such a value shares the same object. Unreachable objects
+/// are reclaimed by a mark and sweep collection.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<Object>>,
+    free: Vec<usize>,
+    threshold: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: vec![],
+            free: vec![],
+            threshold: MIN_THRESHOLD,
+        }
+    }
+
+    pub fn live(&self) -> usize {
+        self.objects.len() - self.free.len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.live() >= self.threshold
+    }
+
+    pub fn alloc(&mut self, object: Object) -> usize {
+        match self.free.pop() {
+            Some(handle) => {
+                self.objects[handle] = Some(object);
+                handle
+            }
+            None => {
+                self.objects.push(Some(object));
+                self.objects.len() - 1
+            }
+        }
+    }
+
+    /// Takes back the latest allocation, which returned `handle`. `reused`
+    /// says whether it took a freed slot rather than adding one.
+    pub fn unalloc(&mut self, handle: usize, reused: bool) {
+        self.objects[handle] = None;
+        match reused {
+            true => self.free.push(handle),
+            false => {
+                self.objects.pop();
+            }
+        }
+    }
+
+    /// How many slots there are, whether in use or free.
+    pub fn slots(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// The object behind `handle`, if there is one.
+    pub fn object(&self, handle: usize) -> Option<&Object> {
+        self.objects.get(handle)?.as_ref()
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = &Object> {
+        self.objects.iter().flatten()
+    }
+
+    fn get(&self, handle: usize) -> &Object {
+        self.objects[handle].as_ref().expect("dangling heap handle")
+    }
+
+    fn get_mut(&mut self, handle: usize) -> &mut Object {
+        self.objects[handle].as_mut().expect("dangling heap handle")
+    }
+
+    pub fn closure(&self, handle: usize) -> &Closure {
+        match self.get(handle) {
+            Object::Closure(closure) => closure,
+            _ => panic!("heap handle {} is not a closure", handle),
+        }
+    }
+
+    pub fn closure_mut(&mut self, handle: usize) -> &mut Closure {
+        match self.get_mut(handle) {
+            Object::Closure(closure) => closure,
+            _ => panic!("heap handle {} is not a closure", handle),
+        }
+    }
+
+    pub fn coroutine_mut(&mut self, handle: usize) -> &mut Coroutine {
+        match self.get_mut(handle) {
+            Object::Coroutine(coroutine) => coroutine,
+            _ => panic!("heap handle {} is not a coroutine", handle),
+        }
+    }
+
+    pub fn channel(&self, handle: usize) -> &Channel {
+        match self.get(handle) {
+            Object::Channel(channel) => channel,
+            _ => panic!("heap handle {} is not a channel", handle),
+        }
+    }
+
+    pub fn channel_mut(&mut self, handle: usize) -> &mut Channel {
+        match self.get_mut(handle) {
+            Object::Channel(channel) => channel,
+            _ => panic!("heap handle {} is not a channel", handle),
+        }
+    }
+
+    pub fn collect<'a>(
+        &mut self,
+        roots: impl IntoIterator<Item = &'a Value>,
+        handles: &[usize],
+    ) -> Collected {
+        let mut marked = vec![false; self.objects.len()];
+        let mut pending: Vec<usize> = handles.to_vec();
+        pending.extend(roots.into_iter().filter_map(handle_of));
+
+        while let Some(handle) = pending.pop() {
+            if std::mem::replace(&mut marked[handle], true) {
+                continue;
+            }
+            self.get(handle).trace(&mut pending);
+        }
+
+        let mut freed = vec![];
+        for (handle, object) in self.objects.iter_mut().enumerate() {
+            if let Some(object) = object.take_if(|_| !marked[handle]) {
+                self.free.push(handle);
+                freed.push((handle, object));
+            }
+        }
+        let threshold = self.threshold;
+        self.threshold = (self.live() * 2).max(MIN_THRESHOLD);
+        Collected { freed, threshold }
+    }
+
+    /// Brings back what `collect` freed. Anything allocated since has to
+    /// be taken back first.
+    pub fn uncollect(&mut self, collected: Collected) {
+        self.free.truncate(self.free.len() - collected.freed.len());
+        for (handle, object) in collected.freed {
+            self.objects[handle] = Some(object);
+        }
+        self.threshold = collected.threshold;
+    }
+}
+
+pub fn handle_of(value: &Value) -> Option<usize> {
+    match value {
+        Value::Closure(handle) | Value::Coroutine(handle) | Value::Channel(handle) => Some(*handle),
+        _ => None,
+    }
+}
+
+impl Heap {
+    pub fn save(&self, out: &mut Writer) {
+        out.word(self.threshold);
+        out.list(&self.objects, |out, object| {
+            match object {
+                None => out.word("-"),
+                Some(Object::Closure(closure)) => {
+                    out.word("closure");
+                    out.string(&closure.function);
+                    out.values(&closure.captures);
+                }
+                Some(Object::Coroutine(coroutine)) => {
+                    out.word("coroutine");
+                    out.word(coroutine.status.name());
+                    coroutine.fiber.save(out);
+                }
+                Some(Object::Channel(channel)) => {
+                    out.word("channel");
+                    out.word(channel.capacity);
+                    let queue: Vec<Value> = channel.queue.iter().cloned().collect();
+                    out.values(&queue);
+                }
+            }
+            out.line();
+        });
+    }
+
+    pub fn load(input: &mut Reader) -> Result<Self, EngineError> {
+        let threshold = input.number()?;
+        let objects = input.list(|input| {
+            let object = match input.word()?.as_str() {
+                "-" => return Ok(None),
+                "closure" => Object::Closure(Closure {
+                    function: input.string()?,
+                    captures: input.values()?,
+                }),
+                "coroutine" => {
+                    let status = match input.word()?.as_str() {
+                        "suspended" => Status::Suspended,
+                        "running" => Status::Running,
+                        "dead" => Status::Dead,
+                        status => return Err(invalid(format!("unknown status `{}`", status))),
+                    };
+                    Object::Coroutine(Coroutine {
+                        status,
+                        fiber: Fiber::load(input)?,
+                    })
+                }
+                "channel" => Object::Channel(Channel {
+                    capacity: input.number()?,
+                    queue: input.values()?.into(),
+                }),
+                word => return Err(invalid(format!("unknown object `{}`", word))),
+            };
+            Ok(Some(object))
+        })?;
+
+        let free = (0..objects.len())
+            .filter(|&i| objects[i].is_none())
+            .collect();
+        Ok(Self {
+            objects,
+            free,
+            threshold,
+        })
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_collect_keeps_reachable_closures() {
+    let mut heap = Heap::new();
+    let inner = heap.alloc(Object::Closure(Closure {
+        function: "inner".into(),
+        captures: vec![Value::Int(1)],
+    }));
+    let outer = heap.alloc(Object::Closure(Closure {
+        function: "outer".into(),
+        captures: vec![Value::Closure(inner)],
+    }));
+    let garbage = heap.alloc(Object::Coroutine(Coroutine {
+        status: Status::Suspended,
+        fiber: Fiber::default(),
+    }));
+
+    heap.collect([&Value::Closure(outer)], &[]);
+
+    assert_eq!(heap.live(), 2);
+    assert_eq!(heap.closure(inner).captures, vec![Value::Int(1)]);
+    assert_eq!(
+        heap.alloc(Object::Closure(Closure {
+            function: "reused".into(),
+            captures: vec![],
+        })),
+        garbage
+    );
+}
@@ -0,0 +1,336 @@
+use std::fmt;
+
+/// A JSON value. Object members keep their order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = JsonParser {
+            chars: input.char_indices().peekable(),
+        };
+        let value = parser.value()?;
+        parser.whitespace();
+        match parser.chars.next() {
+            None => Ok(value),
+            Some((at, _)) => Err(format!("trailing characters at {}", at)),
+        }
+    }
+
+    pub fn object<const N: usize>(members: [(&str, Json); N]) -> Json {
+        Json::Object(
+            members
+                .into_iter()
+                .map(|(name, value)| (name.into(), value))
+                .collect(),
+        )
+    }
+
+    /// The member `name` of an object, or `Null` if there is none.
+    pub fn get(&self, name: &str) -> &Json {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map_or(&Json::Null, |(_, value)| value),
+            _ => &Json::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.into())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<i32> for Json {
+    fn from(n: i32) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Json::Array(values)
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if c < ' ' => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, name)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl JsonParser<'_> {
+    fn whitespace(&mut self) {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.chars.next() {
+            Some((_, c)) if c == expected => Ok(()),
+            Some((at, c)) => Err(format!("expected `{}` at {}, found `{}`", expected, at, c)),
+            None => Err(format!("expected `{}` at end of input", expected)),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        word.chars().try_for_each(|c| self.expect(c))?;
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.chars.peek().copied() {
+            None => Err("unexpected end of input".into()),
+            Some((_, 'n')) => self.keyword("null", Json::Null),
+            Some((_, 't')) => self.keyword("true", Json::Bool(true)),
+            Some((_, 'f')) => self.keyword("false", Json::Bool(false)),
+            Some((_, '"')) => Ok(Json::String(self.string()?)),
+            Some((_, '[')) => self.array(),
+            Some((_, '{')) => self.object(),
+            Some((_, c)) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some((at, c)) => Err(format!("unexpected `{}` at {}", c, at)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let mut text = String::new();
+        while let Some((_, c)) = self
+            .chars
+            .next_if(|(_, c)| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'))
+        {
+            text.push(c);
+        }
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| format!("invalid number `{}`", text))
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .chars
+                .next()
+                .and_then(|(_, c)| c.to_digit(16))
+                .ok_or("invalid `\\u` escape")?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                None => return Err("unterminated string".into()),
+                Some((_, '"')) => return Ok(s),
+                Some((_, '\\')) => {
+                    let c = match self.chars.next() {
+                        Some((_, '"')) => '"',
+                        Some((_, '\\')) => '\\',
+                        Some((_, '/')) => '/',
+                        Some((_, 'b')) => '\u{8}',
+                        Some((_, 'f')) => '\u{c}',
+                        Some((_, 'n')) => '\n',
+                        Some((_, 'r')) => '\r',
+                        Some((_, 't')) => '\t',
+                        Some((_, 'u')) => {
+                            let mut code = self.hex()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect('\\')?;
+                                self.expect('u')?;
+                                let low = self.hex()?;
+                                code = 0x10000
+                                    + ((code - 0xd800) << 10)
+                                    + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        Some((at, c)) => return Err(format!("invalid escape `\\{}` at {}", c, at)),
+                        None => return Err("unterminated string".into()),
+                    };
+                    s.push(c);
+                }
+                Some((_, c)) => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut values = vec![];
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == ']').is_some() {
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            self.whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, ']')) => return Ok(Json::Array(values)),
+                _ => return Err("expected `,` or `]` in array".into()),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.whitespace();
+        if self.chars.next_if(|(_, c)| *c == '}').is_some() {
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let name = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            members.push((name, self.value()?));
+            self.whitespace();
+            match self.chars.next() {
+                Some((_, ',')) => {}
+                Some((_, '}')) => return Ok(Json::Object(members)),
+                _ => return Err("expected `,` or `}` in object".into()),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_json_roundtrip() {
+    let input = r#" {"seq": 3, "type":"request", "arguments": {"lines": [1, 20, -3.5e1], "path": "a\"b\\c\u00e9\ud83d\ude00\n", "ok": true, "none": null, "empty": {}, "list": []}} "#;
+    let json = Json::parse(input).unwrap();
+
+    assert_eq!(json.get("seq").as_usize(), Some(3));
+    assert_eq!(json.get("missing"), &Json::Null);
+    let arguments = json.get("arguments");
+    assert_eq!(arguments.get("lines").as_array()[2], Json::Number(-35.0));
+    assert_eq!(arguments.get("path").as_str(), Some("a\"b\\cé😀\n"));
+    assert_eq!(arguments.get("ok").as_bool(), Some(true));
+
+    let printed = json.to_string();
+    assert_eq!(
+        printed,
+        r#"{"seq":3,"type":"request","arguments":{"lines":[1,20,-35],"path":"a\"b\\cé😀\n","ok":true,"none":null,"empty":{},"list":[]}}"#
+    );
+    assert_eq!(Json::parse(&printed).unwrap(), json);
+
+    for bad in [
+        "",
+        "{",
+        "[1,]",
+        "{\"a\" 1}",
+        "\"abc",
+        "tru",
+        "1 2",
+        "\"\\x\"",
+    ] {
+        assert!(Json::parse(bad).is_err(), "{}", bad);
+    }
+}
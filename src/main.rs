@@ -1,30 +1,341 @@
+pub mod bigint;
 pub mod command;
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
 pub mod eval;
+pub mod heap;
+pub mod json;
+pub mod native;
 pub mod oh;
 pub mod parser;
+pub mod pool;
+pub mod replay;
+pub mod snapshot;
+pub mod store;
+pub mod tokenizer;
+pub mod watch;
 
 use command::EngineError;
 use eval::Evaluator;
+use eval::InterruptHandle;
 use oh::parser::Parser as OhParser;
 use parser::Parser;
+use pool::{isolate, Outcome, Pool};
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use store::Store;
+
+fn main() {
+    match run() {
+        Ok(code) => std::process::exit(code),
+        Err(e @ EngineError::Interrupted { .. }) => {
+            eprintln!("{}", e);
+            std::process::exit(130);
+        }
+        Err(e @ EngineError::Timeout { .. }) => {
+            eprintln!("{}", e);
+            std::process::exit(124);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+static CTRL_C: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn install_ctrl_c_handler() {
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    // Only the first Ctrl-C is caught, so a second one still kills a script
+    // that never reaches an interrupt check, e.g. one blocked in `input`.
+    extern "C" fn on_sigint(_: i32) {
+        CTRL_C.store(true, Ordering::SeqCst);
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+
+    unsafe {
+        signal(SIGINT, on_sigint as extern "C" fn(i32) as usize);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_ctrl_c_handler() {}
+
+// Turns Ctrl-C into an interrupt of whatever evaluators share the handle,
+// so they stop with a backtrace instead of killing the process.
+fn interrupt_on_ctrl_c() -> InterruptHandle {
+    let handle = InterruptHandle::default();
+    let watched = handle.clone();
+
+    install_ctrl_c_handler();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        if CTRL_C.swap(false, Ordering::SeqCst) {
+            watched.interrupt();
+        }
+    });
+
+    handle
+}
+
+struct Options {
+    interrupt: InterruptHandle,
+    timeout: Option<Duration>,
+    store: Option<Store>,
+}
+
+fn evaluator(options: &Options) -> Evaluator {
+    let mut evaluator = Evaluator::new();
+    evaluator.set_interrupt_handle(options.interrupt.clone());
+    evaluator.set_timeout(options.timeout);
+    evaluator.set_store(options.store.clone());
+    evaluator
+}
+
+fn run() -> Result<i32, EngineError> {
+    let mut args = std::env::args().skip(1).peekable();
+    // Subcommands own standard output, so they come before anything prints.
+    if args.next_if_eq("dap").is_some() {
+        if args.next().is_some() {
+            return Err(EngineError::Usage("dap".into()));
+        }
+        let input = std::io::BufReader::new(std::io::stdin());
+        return dap::serve(input, std::io::stdout());
+    }
+    if args.next_if_eq("debug").is_some() {
+        let (Some(file), None) = (args.next(), args.next()) else {
+            return Err(EngineError::Usage("debug <file>".into()));
+        };
+        let program = Parser::new().parse_file(&file)?;
+        let mut evaluator = Evaluator::new();
+        evaluator.set_interrupt_handle(interrupt_on_ctrl_c());
+        return debugger::repl(
+            &program,
+            evaluator,
+            &file,
+            std::io::stdin().lock(),
+            std::io::stdout(),
+        );
+    }
 
-fn main() -> Result<(), EngineError> {
     let mut oh_parser = OhParser::new();
     let contents = std::fs::read_to_string("./samples/oh/tokens.oh").unwrap();
     let result = oh_parser.parse(&contents)?;
     println!("{:?}", result);
 
-    for file in std::env::args().skip(1) {
-        let contents = std::fs::read_to_string(file).unwrap();
+    let mut disassemble = false;
+    let mut jobs = 1;
+    let mut timeout = None;
+    let mut snapshot = None;
+    let mut resume = None;
+    let mut store = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut files = vec![];
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--disasm" => disassemble = true,
+            "--jobs" => {
+                jobs = args
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| EngineError::Usage("--jobs <N>, N > 0".into()))?;
+            }
+            "--timeout" => {
+                let ms = args
+                    .next()
+                    .and_then(|ms| ms.parse().ok())
+                    .ok_or_else(|| EngineError::Usage("--timeout <milliseconds>".into()))?;
+                timeout = Some(Duration::from_millis(ms));
+            }
+            "--store" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| EngineError::Usage("--store <file>".into()))?;
+                store = Some(Store::new(path));
+            }
+            "--record" | "--replay" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| EngineError::Usage(format!("{} <file>", arg)))?;
+                match arg.as_str() {
+                    "--record" => record = Some(path),
+                    _ => replay = Some(path),
+                }
+            }
+            "--snapshot" | "--resume" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| EngineError::Usage(format!("{} <file>", arg)))?;
+                match arg.as_str() {
+                    "--snapshot" => snapshot = Some(path),
+                    _ => resume = Some(path),
+                }
+            }
+            _ => files.push(arg),
+        }
+    }
+
+    if disassemble {
+        for file in files {
+            print!("{}", Parser::new().parse_file(file)?);
+        }
+        return Ok(0);
+    }
+
+    let options = Options {
+        interrupt: interrupt_on_ctrl_c(),
+        timeout,
+        store,
+    };
+
+    if jobs > 1 {
+        if snapshot.is_some()
+            || resume.is_some()
+            || options.store.is_some()
+            || record.is_some()
+            || replay.is_some()
+        {
+            return Err(EngineError::Usage(
+                "--snapshot, --resume, --store, --record and --replay cannot be used with --jobs"
+                    .into(),
+            ));
+        }
+        return run_parallel(files, jobs, &options);
+    }
+    if resume.is_some() && files.len() != 1 {
+        return Err(EngineError::Usage("--resume <snapshot> <file>".into()));
+    }
+    if record.is_some() && replay.is_some() {
+        return Err(EngineError::Usage(
+            "--record and --replay cannot be used together".into(),
+        ));
+    }
+    if (record.is_some() || replay.is_some()) && files.len() != 1 {
+        return Err(EngineError::Usage(
+            "--record|--replay <journal> <file>".into(),
+        ));
+    }
+
+    let mut code = 0;
+
+    for file in files {
         let parser = Parser::new();
-        let commands = parser.parse(&contents)?;
-        let mut eval = Evaluator::new();
-        let result = eval.evaluate(&commands)?;
+        let commands = parser.parse_file(file)?;
+
+        let mut eval = match &resume {
+            Some(path) => {
+                let saved = std::fs::read_to_string(path)
+                    .map_err(|e| EngineError::Io(format!("{}: {}", path, e)))?;
+                let mut eval = Evaluator::restore(&commands, &saved)?;
+                eval.set_interrupt_handle(options.interrupt.clone());
+                eval.set_timeout(options.timeout);
+                eval.set_store(options.store.clone());
+                eval
+            }
+            None => evaluator(&options),
+        };
+        if record.is_some() {
+            eval.record();
+        }
+        if let Some(path) = &replay {
+            let recording = std::fs::read_to_string(path)
+                .map_err(|e| EngineError::Io(format!("{}: {}", path, e)))?;
+            eval.replay(&recording)?;
+        }
+        let result = match &resume {
+            Some(_) => eval.resume(&commands),
+            None => eval.evaluate(&commands),
+        };
+
+        // Saved whatever the outcome, since a failing run is the one worth
+        // replaying.
+        if let (Some(path), Some(recording)) = (&record, eval.recording()) {
+            save_atomically(path, &recording)?;
+        }
+
+        let result = match (result, &snapshot) {
+            (
+                Err(e @ (EngineError::Interrupted { .. } | EngineError::Timeout { .. })),
+                Some(path),
+            ) => {
+                save_atomically(path, &eval.snapshot(&commands))?;
+                eprintln!("snapshot saved to {}", path);
+                return Err(e);
+            }
+            (result, _) => result?,
+        };
 
         println!("Result -> {}", result);
+
+        code = result.exit_code();
+        if code != 0 {
+            break;
+        }
     }
 
-    Ok(())
+    Ok(code)
+}
+
+// Writes next to the destination first, so a crash never leaves a truncated
+// snapshot behind.
+fn save_atomically(path: &str, contents: &str) -> Result<(), EngineError> {
+    let partial = format!("{}.partial", path);
+    std::fs::write(&partial, contents)
+        .and_then(|_| std::fs::rename(&partial, path))
+        .map_err(|e| EngineError::Output(format!("{}: {}", path, e)))
+}
+
+// Runs every file in its own evaluator on a pool of `jobs` threads, printing
+// each file's output and result in command line order.
+fn run_parallel(files: Vec<String>, jobs: usize, options: &Options) -> Result<i32, EngineError> {
+    let pool = Pool::new(jobs);
+    let pending: Vec<_> = files
+        .into_iter()
+        .map(|file| {
+            let evaluator = evaluator(options);
+            pool.spawn(move || match Parser::new().parse_file(file) {
+                Ok(program) => isolate(evaluator, &program),
+                Err(e) => Outcome {
+                    output: vec![],
+                    result: Err(e),
+                },
+            })
+        })
+        .collect();
+
+    let mut code = 0;
+
+    for pending in pending {
+        let outcome = pending.wait();
+        std::io::stdout()
+            .write_all(&outcome.output)
+            .map_err(|e| EngineError::Output(e.to_string()))?;
+        let result = outcome.result?;
+
+        println!("Result -> {}", result);
+
+        code = result.exit_code();
+        if code != 0 {
+            break;
+        }
+    }
+
+    Ok(code)
 }
 
 #[test]
@@ -39,7 +350,7 @@ fn test1() -> Result<(), EngineError> {
             Command::GetVar("a".into()),
         ],
         functions: HashMap::from([(String::from("main"), 0)]),
-        labels: Default::default(),
+        ..Default::default()
     };
 
     let mut evaluator = Evaluator::new();
@@ -68,7 +379,7 @@ fn test2() -> Result<(), EngineError> {
 #[test]
 fn test_sub() -> Result<(), EngineError> {
     use command::Value;
-    let intput = "func main\npush 5\npush 10\nsub\npop\nend";
+    let intput = "func main\npush 5\npush 10\nsub\nend";
     let parser = Parser::new();
     let commands = parser.parse(intput)?;
 
@@ -79,3 +390,1205 @@ fn test_sub() -> Result<(), EngineError> {
     assert_eq!(result, Value::Int(5));
     Ok(())
 }
+
+#[test]
+fn test_string_literal() -> Result<(), EngineError> {
+    use command::Value;
+    let intput = "func main\npush \"hello world\\n\\t\\\"x\\\"\"\nend";
+    let parser = Parser::new();
+    let commands = parser.parse(intput)?;
+
+    let mut evaluator = Evaluator::new();
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::String("hello world\n\t\"x\"".into()));
+    assert_eq!(result.to_string(), "\"hello world\\n\\t\\\"x\\\"\"");
+    Ok(())
+}
+
+#[test]
+fn test_comments_and_consts() -> Result<(), EngineError> {
+    use command::Value;
+    let intput =
+        "; header comment\n\n.const ANSWER 42\nfunc main # entry\n  push ANSWER ; the answer\nend";
+    let parser = Parser::new();
+    let commands = parser.parse(intput)?;
+
+    let mut evaluator = Evaluator::new();
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(42));
+    Ok(())
+}
+
+#[test]
+fn test_directive_errors() {
+    let parser = Parser::new();
+
+    let Err(EngineError::Syntax(diagnostics)) =
+        parser.parse(".const A 1\n.const A 2\n.bogus\n.global nowhere\nfunc main\nend")
+    else {
+        panic!("expected syntax errors");
+    };
+
+    let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        [
+            "duplicate definition of constant `A`",
+            "unknown directive `.bogus`",
+            "unknown symbol `nowhere`",
+        ]
+    );
+}
+
+#[test]
+fn test_parse_diagnostics() {
+    let parser = Parser::new();
+    let intput = "func main\n  pusj 10\n  jz\n  push 1 2\nloop:\nloop:\nend\nfunc main\nend";
+
+    let Err(EngineError::Syntax(diagnostics)) = parser.parse(intput) else {
+        panic!("expected syntax errors");
+    };
+
+    let summary: Vec<_> = diagnostics
+        .iter()
+        .map(|d| (d.line, d.column, d.message.as_str(), d.help.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (2, 3, "unknown command `pusj`", Some("did you mean `push`?")),
+            (
+                3,
+                6,
+                "`jz` is missing its label operand",
+                Some("expected `jz <label>`")
+            ),
+            (4, 10, "unexpected operand for `push`", None),
+            (
+                6,
+                1,
+                "duplicate definition of label `main.loop`",
+                Some("previous definition at <input>:5")
+            ),
+            (
+                8,
+                6,
+                "duplicate definition of function `main`",
+                Some("previous definition at <input>:1")
+            ),
+        ]
+    );
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "error: unknown command `pusj`\n --> <input>:2:3\n  |\n2 |   pusj 10\n  |   ^^^^\n  = help: did you mean `push`?"
+    );
+
+    let Err(EngineError::Syntax(diagnostics)) = parser.parse("func main\nspwn\nend") else {
+        panic!("expected syntax errors");
+    };
+    assert_eq!(
+        diagnostics[0].help.as_deref(),
+        Some("did you mean `spawn`?")
+    );
+}
+
+#[test]
+fn test_include() -> Result<(), EngineError> {
+    use command::Value;
+    let parser = Parser::new();
+    let commands = parser.parse_file("samples/include.onehour")?;
+
+    let mut evaluator = Evaluator::new();
+
+    let result = evaluator.evaluate(&commands)?;
+
+    assert_eq!(result, Value::Int(120));
+    Ok(())
+}
+
+#[test]
+fn test_function_scoped_labels() -> Result<(), EngineError> {
+    use command::Value;
+    let intput = "func main\npush 0\njz skip\nloop:\npush 1\nskip:\ncall other\nend\nfunc other\npush 1\njp loop\nloop:\npush 7\nret";
+    let parser = Parser::new();
+    let commands = parser.parse(intput)?;
+
+    assert_eq!(commands.labels["main.loop"], 2);
+    assert_eq!(commands.labels["other.loop"], 7);
+    assert!(commands.to_string().contains("jp other.loop"));
+
+    let mut evaluator = Evaluator::new();
+
+    assert_eq!(evaluator.evaluate(&commands)?, Value::Int(7));
+    Ok(())
+}
+
+#[test]
+fn test_cross_function_jump() -> Result<(), EngineError> {
+    let parser = Parser::new();
+    let intput = "func main\npush 0\njz elsewhere\nend\nfunc other\nelsewhere:\nret";
+
+    let Err(EngineError::Syntax(diagnostics)) = parser.parse(intput) else {
+        panic!("expected syntax errors");
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "unknown label `elsewhere`");
+    assert_eq!(diagnostics[0].line, 3);
+    assert!(diagnostics[0]
+        .help
+        .as_deref()
+        .unwrap()
+        .contains("belongs to function `other`"));
+
+    let commands = parser.parse(&format!(".global elsewhere\n{}", intput))?;
+    assert_eq!(commands.labels["elsewhere"], 3);
+    assert!(commands.to_string().contains("jz elsewhere\n"));
+    Ok(())
+}
+
+#[cfg(test)]
+fn eval_source(intput: &str) -> Result<command::Value, EngineError> {
+    let parser = Parser::new();
+    let commands = parser.parse(intput)?;
+
+    let mut evaluator = Evaluator::new();
+
+    evaluator.evaluate(&commands)
+}
+
+#[test]
+fn test_stack_words() -> Result<(), EngineError> {
+    use command::Value;
+
+    assert_eq!(
+        eval_source("func main\npush 1\ndup\nadd\nend")?,
+        Value::Int(2)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\nswap\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\nover\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\npush 3\nrot\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\npush 3\nrot\ndrop\nend")?,
+        Value::Int(3)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\ndrop\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\npush 3\npick 2\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\npick 0\nend")?,
+        Value::Int(2)
+    );
+
+    assert!(matches!(
+        eval_source("func main\ndup\nend"),
+        Err(EngineError::EmptyStack)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 1\nover\nend"),
+        Err(EngineError::EmptyStack)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 1\npick 1\nend"),
+        Err(EngineError::EmptyStack)
+    ));
+    assert!(matches!(
+        eval_source("func main\npick -1\nend"),
+        Err(EngineError::Syntax(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_stack_effects() {
+    use command::{Command, StackEffect};
+
+    assert_eq!(Command::Dup.stack_effect(), Some(StackEffect::new(1, 2)));
+    assert_eq!(Command::Rot.stack_effect(), Some(StackEffect::new(3, 3)));
+    assert_eq!(
+        Command::Pick(2).stack_effect(),
+        Some(StackEffect::new(3, 4))
+    );
+    assert_eq!(Command::FuncCall("f".into()).stack_effect(), None);
+}
+
+#[test]
+fn test_store_and_load() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func main\npush 6\npush 7\nmul\nstore answer\nload answer\nload answer\nadd\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(84));
+
+    let intput = "func main\nset x 5\nload x\npush 1\nadd\nstore x\nget x\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(6));
+
+    assert!(matches!(
+        eval_source("func main\nload nope\nend"),
+        Err(EngineError::MissingVariable(_))
+    ));
+    assert!(matches!(
+        eval_source("func main\nstore x\nend"),
+        Err(EngineError::EmptyStack)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_program_result() -> Result<(), EngineError> {
+    use command::Value;
+
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\nend")?,
+        Value::Int(2)
+    );
+    assert_eq!(eval_source("func main\npush 1\npop\nend")?, Value::Nothing);
+    assert_eq!(eval_source("func main\npush 4\nret")?, Value::Int(4));
+    assert_eq!(
+        eval_source("func main\npush 1\ncall f\npush 2\nend\nfunc f\npush 3\nhalt")?,
+        Value::Int(3)
+    );
+    assert_eq!(
+        eval_source("func main\ncall f\npush 2\nend\nfunc f\npush 9\nexit 3")?,
+        Value::Int(3)
+    );
+    assert_eq!(eval_source("func main\npush 7\nexit\nend")?, Value::Int(7));
+    assert!(matches!(
+        eval_source("func main\npush \"x\"\nexit\nend"),
+        Err(EngineError::MismatchType)
+    ));
+
+    assert_eq!(Value::Int(3).exit_code(), 3);
+    assert_eq!(Value::Int(256).exit_code(), 255);
+    assert_eq!(Value::Int(4294967296).exit_code(), 255);
+    assert_eq!(Value::Int(-1).exit_code(), 1);
+    assert_eq!(Value::Nothing.exit_code(), 0);
+    assert_eq!(Value::String("ok".into()).exit_code(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_cmp_semantics() -> Result<(), EngineError> {
+    use command::Value;
+
+    // `cmp` pops the top of the stack as lhs: it pushes -1 when the value
+    // pushed last is the greater one, and 1 when it is the smaller one.
+    assert_eq!(
+        eval_source("func main\npush 1\npush 2\ncmp\nend")?,
+        Value::Int(-1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 2\npush 1\ncmp\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 2\npush 2\ncmp\nend")?,
+        Value::Int(0)
+    );
+    assert!(matches!(
+        eval_source("func main\npush 1\npush \"a\"\ncmp\nend"),
+        Err(EngineError::MismatchType)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_comparisons() -> Result<(), EngineError> {
+    use command::Value;
+
+    let cases = [
+        ("1", "2", "lt", true),
+        ("2", "1", "lt", false),
+        ("2", "2", "le", true),
+        ("3", "2", "gt", true),
+        ("2", "3", "ge", false),
+        ("2", "2", "eq", true),
+        ("2", "3", "ne", true),
+        ("\"abc\"", "\"abd\"", "lt", true),
+        ("\"x\"", "1", "eq", false),
+        ("true", "true", "eq", true),
+    ];
+
+    for (a, b, op, expected) in cases {
+        let intput = format!("func main\npush {}\npush {}\n{}\nend", a, b, op);
+        assert_eq!(eval_source(&intput)?, Value::Bool(expected), "{}", intput);
+    }
+
+    assert!(matches!(
+        eval_source("func main\npush true\npush 1\nlt\nend"),
+        Err(EngineError::MismatchType)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_logic_and_bitwise() -> Result<(), EngineError> {
+    use command::Value;
+
+    let cases = [
+        ("true", "false", "and", Value::Bool(false)),
+        ("true", "false", "or", Value::Bool(true)),
+        ("true", "true", "xor", Value::Bool(false)),
+        ("12", "10", "and", Value::Int(8)),
+        ("12", "10", "or", Value::Int(14)),
+        ("12", "10", "xor", Value::Int(6)),
+        ("1", "4", "shl", Value::Int(16)),
+        ("-16", "2", "shr", Value::Int(-4)),
+    ];
+
+    for (a, b, op, expected) in cases {
+        let intput = format!("func main\npush {}\npush {}\n{}\nend", a, b, op);
+        assert_eq!(eval_source(&intput)?, expected, "{}", intput);
+    }
+
+    assert_eq!(
+        eval_source("func main\npush true\nnot\nend")?,
+        Value::Bool(false)
+    );
+    assert_eq!(eval_source("func main\npush 0\nnot\nend")?, Value::Int(-1));
+    assert!(matches!(
+        eval_source("func main\npush 1\npush 64\nshl\nend"),
+        Err(EngineError::InvalidShift(64))
+    ));
+    assert!(matches!(
+        eval_source("func main\npush true\npush 1\nand\nend"),
+        Err(EngineError::MismatchType)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_boolean_branches() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func main\npush 0\nstore i\nloop:\nload i\npush 5\nge\njt done\nload i\npush 1\nadd\nstore i\njmp loop\ndone:\nload i\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(5));
+
+    let intput = "func main\npush false\njf skip\npush 1\nend\nskip:\npush 2\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(2));
+
+    assert!(matches!(
+        eval_source("func main\npush 1\njt x\nx:\nend"),
+        Err(EngineError::MismatchType)
+    ));
+    assert_eq!(Value::Bool(true).exit_code(), 0);
+    assert_eq!(Value::Bool(false).exit_code(), 1);
+    Ok(())
+}
+
+#[test]
+fn test_checked_arithmetic() -> Result<(), EngineError> {
+    let max = i64::MAX;
+    let min = i64::MIN;
+
+    // Checked arithmetic promotes to arbitrary precision instead of failing.
+    for (intput, expected) in [
+        (
+            format!("func main\npush 1\npush {}\nadd\nend", max),
+            "9223372036854775808",
+        ),
+        (
+            format!("func main\npush 1\npush {}\nsub\nend", min),
+            "-9223372036854775809",
+        ),
+        (
+            format!("func main\npush 2\npush {}\nmul\nend", max),
+            "18446744073709551614",
+        ),
+        (
+            format!("func main\npush -1\npush {}\ndiv\nend", min),
+            "9223372036854775808",
+        ),
+        (
+            format!("func main\npush {}\nneg\nend", min),
+            "9223372036854775808",
+        ),
+        (
+            format!("func main\npush {}\nabs\nend", min),
+            "9223372036854775808",
+        ),
+        (
+            "func main\npush 64\npush 2\npow\nend".to_string(),
+            "18446744073709551616",
+        ),
+    ] {
+        assert_eq!(eval_source(&intput)?.to_string(), expected, "{}", intput);
+    }
+
+    assert!(matches!(
+        eval_source("func main\npush 0\npush 1\ndiv\nend"),
+        Err(EngineError::DivisionByZero)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 0\npush 1\ndiv.wrap\nend"),
+        Err(EngineError::DivisionByZero)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 0\npush 1\nmod\nend"),
+        Err(EngineError::DivisionByZero)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush -1\npush 2\npow\nend"),
+        Err(EngineError::NegativeExponent)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_wrapping_and_saturating_arithmetic() -> Result<(), EngineError> {
+    use command::Value;
+    let max = i64::MAX;
+    let min = i64::MIN;
+
+    let cases = [
+        (format!("push 1\npush {}\nadd.wrap", max), min),
+        (format!("push 1\npush {}\nadd.sat", max), max),
+        (format!("push 1\npush {}\nsub.wrap", min), max),
+        (format!("push 1\npush {}\nsub.sat", min), min),
+        (format!("push 2\npush {}\nmul.wrap", max), -2),
+        (format!("push -2\npush {}\nmul.sat", max), min),
+        (format!("push -1\npush {}\ndiv.wrap", min), min),
+        (format!("push -1\npush {}\ndiv.sat", min), max),
+        (format!("push {}\nneg.wrap", min), min),
+        (format!("push {}\nneg.sat", min), max),
+        (format!("push {}\nabs.sat", min), max),
+        ("push 64\npush 2\npow.wrap".to_string(), 0),
+        ("push 63\npush -2\npow.sat".to_string(), min),
+        ("push 65\npush -2\npow.sat".to_string(), min),
+        ("push 4000000000\npush -1\npow".to_string(), 1),
+    ];
+
+    for (body, expected) in cases {
+        let intput = format!("func main\n{}\nend", body);
+        assert_eq!(eval_source(&intput)?, Value::Int(expected), "{}", intput);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_mod_neg_abs_pow() -> Result<(), EngineError> {
+    use command::Value;
+
+    assert_eq!(
+        eval_source("func main\npush 3\npush 10\nmod\nend")?,
+        Value::Int(1)
+    );
+    assert_eq!(
+        eval_source("func main\npush 3\npush -10\nmod\nend")?,
+        Value::Int(-1)
+    );
+    assert_eq!(eval_source("func main\npush 5\nneg\nend")?, Value::Int(-5));
+    assert_eq!(eval_source("func main\npush -5\nabs\nend")?, Value::Int(5));
+    assert_eq!(
+        eval_source("func main\npush 10\npush 2\npow\nend")?,
+        Value::Int(1024)
+    );
+    assert_eq!(
+        eval_source("func main\npush 0\npush 7\npow\nend")?,
+        Value::Int(1)
+    );
+    assert!(matches!(
+        eval_source("func main\npush 1\nadd.wrp\nend"),
+        Err(EngineError::Syntax(_))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_bigint_promotion() -> Result<(), EngineError> {
+    use command::Value;
+    let max = i64::MAX;
+
+    let result = eval_source(&format!("func main\npush 1\npush {}\nadd\nend", max))?;
+    assert_eq!(result.to_string(), "9223372036854775808");
+    assert!(matches!(result, Value::BigInt(_)));
+
+    let result = eval_source(&format!("func main\npush {}\ndup\nmul\nend", max))?;
+    assert_eq!(result.to_string(), "85070591730234615847396907784232501249");
+
+    let result = eval_source("func main\npush 100\npush 2\npow\nend")?;
+    assert_eq!(result.to_string(), "1267650600228229401496703205376");
+
+    // Results that fit are converted back to plain integers.
+    let intput = format!("func main\npush 1\npush {}\nadd\npush -1\nadd\nend", max);
+    assert_eq!(eval_source(&intput)?, Value::Int(max));
+    assert_eq!(eval_source("func main\npush 5n\nend")?, Value::Int(5));
+    Ok(())
+}
+
+#[test]
+fn test_bigint_literals_and_operations() -> Result<(), EngineError> {
+    use command::Value;
+    let big = "123456789012345678901234567890";
+
+    let cases = [
+        (
+            format!("push 1\npush {}n\nadd", big),
+            "123456789012345678901234567891",
+        ),
+        (
+            format!("push 1\npush {}n\nsub", big),
+            "123456789012345678901234567889",
+        ),
+        (
+            format!("push -2\npush {}n\nmul", big),
+            "-246913578024691357802469135780",
+        ),
+        (
+            format!("push 1000000000000n\npush {}n\ndiv", big),
+            "123456789012345678",
+        ),
+        (
+            format!("push 1000000000000n\npush {}n\nmod", big),
+            "901234567890",
+        ),
+        (
+            format!("push {}0n\npush {}n\nmod.sat", big, big),
+            "9223372036854775807",
+        ),
+        (
+            format!("push {}0n\npush {}n\nmod.wrap", big, big),
+            "-4362896299872285998",
+        ),
+        (
+            format!("push {}n\nneg", big),
+            "-123456789012345678901234567890",
+        ),
+        (format!("push -{}n\nabs", big), big),
+        (
+            format!("push 2\npush {}n\npow", big),
+            "15241578753238836750495351562536198787501905199875019052100",
+        ),
+        (
+            format!("push {}n\npush 1\nadd.wrap", big),
+            "-4362896299872285997",
+        ),
+        (
+            format!("push {}n\npush 1\nadd.sat", big),
+            "9223372036854775807",
+        ),
+        (
+            format!("push -{}n\npush 1\nmul.sat", big),
+            "-9223372036854775808",
+        ),
+    ];
+
+    for (body, expected) in cases {
+        let intput = format!("func main\n{}\nend", body);
+        assert_eq!(eval_source(&intput)?.to_string(), expected, "{}", intput);
+    }
+
+    let program = Parser::new().parse(&format!("func main\npush {}n\nend", big))?;
+    assert!(program.to_string().contains(&format!("push {}n", big)));
+
+    assert!(matches!(
+        eval_source(&format!("func main\npush {}\nend", big)),
+        Err(EngineError::Syntax(_))
+    ));
+    assert!(matches!(
+        eval_source(&format!("func main\npush 0\npush {}n\ndiv\nend", big)),
+        Err(EngineError::DivisionByZero)
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 100000000\npush 3\npow\nend"),
+        Err(EngineError::Overflow)
+    ));
+    assert!(matches!(
+        eval_source(&format!("func main\npush {}n\npush 1\nand\nend", big)),
+        Err(EngineError::MismatchType)
+    ));
+    assert_eq!(
+        eval_source("func main\npush 100000000\npush 3\npow.sat\nend")?,
+        Value::Int(i64::MAX)
+    );
+    assert_eq!(
+        eval_source("func main\npush 100000000\npush 2\npow.wrap\nend")?,
+        Value::Int(0)
+    );
+    Ok(())
+}
+
+#[test]
+fn test_bigint_comparisons() -> Result<(), EngineError> {
+    use command::Value;
+    let big = "123456789012345678901234567890";
+
+    let cases = [
+        (format!("push 1\npush {}n\nlt", big), Value::Bool(true)),
+        (format!("push -{}n\npush -1\nlt", big), Value::Bool(true)),
+        (
+            format!("push {}n\npush {}n\neq", big, big),
+            Value::Bool(true),
+        ),
+        (format!("push {}n\npush 1\nge", big), Value::Bool(true)),
+        (format!("push 1\npush {}n\ncmp", big), Value::Int(-1)),
+    ];
+
+    for (body, expected) in cases {
+        let intput = format!("func main\n{}\nend", body);
+        assert_eq!(eval_source(&intput)?, expected, "{}", intput);
+    }
+
+    let intput = format!(
+        "func main\npush {}n\njp big\npush 0\nend\nbig:\npush 1\nend",
+        big
+    );
+    assert_eq!(eval_source(&intput)?, Value::Int(1));
+    Ok(())
+}
+
+#[test]
+fn test_try_catches_runtime_errors() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func main\ntry failed\npush 1\nload missing\nendtry\nend\nfailed:\nerrkind\nend";
+    assert_eq!(
+        eval_source(intput)?,
+        Value::String("missing_variable".into())
+    );
+
+    let intput = "func main\ntry failed\npush 0\npush 1\ndiv\nendtry\nend\nfailed:\nerrline\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(5));
+
+    let intput = "func main\ntry failed\npush 1\nendtry\nend\nfailed:\npush 2\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(1));
+    Ok(())
+}
+
+#[test]
+fn test_throw_unwinds_calls() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func inner\npush 9\npush \"boom\"\nthrow\nret\nfunc outer\ncall inner\nret\nfunc main\npush 7\ntry failed\npush 8\ncall outer\nendtry\nend\nfailed:\nerrmsg\nswap\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(7));
+
+    let intput =
+        "func main\ntry outer\ntry inner\npush \"x\"\nthrow\ninner:\nthrow\nouter:\nerrkind\nend";
+    assert_eq!(eval_source(intput)?, Value::String("thrown".into()));
+
+    assert!(matches!(
+        eval_source("func main\npush \"boom\"\nthrow\nend"),
+        Err(EngineError::Thrown(_))
+    ));
+    assert!(matches!(
+        eval_source("func main\nendtry\nend"),
+        Err(EngineError::NoHandler)
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_handlers_do_not_outlive_their_frame() {
+    let intput =
+        "func guard\ntry failed\nret\nfailed:\nret\nfunc main\ncall guard\nload missing\nend";
+    assert!(matches!(
+        eval_source(intput),
+        Err(EngineError::MissingVariable(_))
+    ));
+}
+
+#[test]
+fn test_resource_limits_are_fatal() {
+    let intput = "func main\ntry failed\npush 1000000000\npush 2\npow\nendtry\nend\nfailed:\nend";
+    assert!(matches!(eval_source(intput), Err(EngineError::Overflow)));
+
+    let intput =
+        "func loop\ncall loop\nadd\nret\nfunc main\ntry failed\ncall loop\nendtry\nend\nfailed:\nend";
+    assert!(matches!(
+        eval_source(intput),
+        Err(EngineError::StackOverflow)
+    ));
+}
+
+#[test]
+fn test_tail_calls_run_in_constant_depth() -> Result<(), EngineError> {
+    use command::Value;
+
+    let countdown = "func countdown\ndup\njz done\npush -1\nadd\n{}\ndone:\nret\nfunc main\npush 1000000\ncall countdown\nend";
+    for call in ["call countdown\nret", "tailcall countdown"] {
+        let intput = countdown.replace("{}", call);
+        assert_eq!(eval_source(&intput)?, Value::Int(0));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tailcall_inside_try() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func f\npush 1\nret\nfunc g\ntry failed\ntailcall f\nfailed:\nerrkind\nret\nfunc main\ncall g\nend";
+    assert_eq!(eval_source(intput)?, Value::String("tail_call".into()));
+
+    let intput = "func f\nload missing\nret\nfunc g\ntry failed\ncall f\nret\nfailed:\nerrkind\nret\nfunc main\ncall g\nend";
+    assert_eq!(
+        eval_source(intput)?,
+        Value::String("missing_variable".into())
+    );
+    Ok(())
+}
+
+#[test]
+fn test_function_values() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func double\ndup\nadd\nret\nfunc square\ndup\nmul\nret\nfunc apply\nicall\nret\nfunc main\npushfn double\nstore f\npush 5\nload f\ncall apply\npushfn square\ncall apply\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(100));
+
+    let intput = "func main\npushfn print\nend";
+    assert_eq!(eval_source(intput)?, Value::Function("print".into()));
+
+    assert!(matches!(
+        eval_source("func main\npush 1\nicall\nend"),
+        Err(EngineError::MismatchType)
+    ));
+    assert!(matches!(
+        eval_source("func main\npushfn nope\nend"),
+        Err(EngineError::UnknownSymbol(_))
+    ));
+    assert!(matches!(
+        eval_source("func helper\npush 1\nret"),
+        Err(EngineError::UnknownSymbol(name)) if name == "main"
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_native_functions() -> Result<(), EngineError> {
+    use command::Value;
+
+    let program = Parser::new()
+        .parse("func main\npush 7\npush 2\npushfn sub\nicall\npush 1\ncall sub\nend")?;
+    let mut evaluator = Evaluator::new();
+    evaluator.register("sub", 2, |args| match args {
+        [Value::Int(a), Value::Int(b)] => Ok(Value::Int(a - b)),
+        _ => Err(EngineError::MismatchType),
+    });
+
+    assert_eq!(evaluator.evaluate(&program)?, Value::Int(4));
+    Ok(())
+}
+
+#[test]
+fn test_closures_share_captured_slots() -> Result<(), EngineError> {
+    use command::Value;
+
+    let counter = "func next\ngetcap 0\npush 1\nadd\ndup\nsetcap 0\nret\nfunc counter\nclosure next 1\nret\nfunc main\npush 10\ncall counter\nstore a\nload a\nicall\npop\nload a\nicall\nend";
+    assert_eq!(eval_source(counter)?, Value::Int(12));
+
+    let adder = "func add_captured\ngetcap 0\nadd\nret\nfunc main\npush 3\nclosure add_captured 1\nstore add3\npush 4\nload add3\nicall\nend";
+    assert_eq!(eval_source(adder)?, Value::Int(7));
+
+    assert!(matches!(
+        eval_source("func main\ngetcap 0\nend"),
+        Err(EngineError::NoCapture(0))
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_unreachable_closures_are_collected() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func f\ngetcap 0\nret\nfunc main\npush 0\nclosure f 1\nstore kept\npush 10000\nloop:\ndup\njz done\ndup\nclosure f 1\nstore last\npush -1\nadd\njmp loop\ndone:\nload kept\nicall\nend";
+    let program = Parser::new().parse(intput)?;
+    let mut evaluator = Evaluator::new();
+
+    assert_eq!(evaluator.evaluate(&program)?, Value::Int(0));
+    assert!(evaluator.live_objects() < 1000);
+    Ok(())
+}
+
+#[test]
+fn test_coroutines_transfer_values() -> Result<(), EngineError> {
+    use command::Value;
+
+    // A generator yielding 1, 2, 3 and then returning 0; main sums what it
+    // receives until the generator is dead.
+    let intput = "func numbers\npop\npush 1\nloop:\ndup\nyield\npop\npush 1\nadd\ndup\npush 4\nlt\njt loop\npush 0\nret\nfunc main\npushfn numbers\ncoroutine\nstore gen\npush 0\nnext:\npush 0\nload gen\nresume\nadd\nload gen\nstatus\npush \"dead\"\neq\njf next\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(6));
+
+    let intput = "func echo\npush 1\nadd\nyield\npush 10\nmul\nret\nfunc main\npushfn echo\ncoroutine\nstore co\npush 4\nload co\nresume\nload co\nresume\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(50));
+    Ok(())
+}
+
+#[test]
+fn test_coroutine_errors() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func done\nret\nfunc main\npushfn done\ncoroutine\nstore co\npush 1\nload co\nresume\npush 1\nload co\nresume\nend";
+    assert!(matches!(
+        eval_source(intput),
+        Err(EngineError::NotResumable("dead"))
+    ));
+    assert!(matches!(
+        eval_source("func main\npush 1\nyield\nend"),
+        Err(EngineError::NotInCoroutine)
+    ));
+
+    let intput = "func bad\nload missing\nret\nfunc main\npushfn bad\ncoroutine\nstore co\ntry failed\npush 1\nload co\nresume\nendtry\nend\nfailed:\nerrline\nload co\nstatus\nend";
+    assert_eq!(eval_source(intput)?, Value::String("dead".into()));
+    let intput = intput.replace("status\nend", "pop\nend");
+    assert_eq!(eval_source(&intput)?, Value::Int(2));
+    Ok(())
+}
+
+#[test]
+fn test_tasks_and_channels() -> Result<(), EngineError> {
+    use command::Value;
+
+    // Three producers each send their argument ten times through a channel
+    // of capacity 2; main receives all thirty values.
+    let intput = "func producer\npush 10\nloop:\nover\nload chan\nsend\npush -1\nadd\ndup\njp loop\nret\nfunc main\npush 2\nchannel\nstore chan\npush 1\npushfn producer\nspawn\npop\npush 2\npushfn producer\nspawn\npop\npush 3\npushfn producer\nspawn\npop\npush 0\npush 30\nstore left\nnext:\nload chan\nrecv\nadd\nload left\npush -1\nadd\ndup\nstore left\njp next\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(60));
+
+    let intput = "func square\ndup\nmul\nret\nfunc main\npush 7\npushfn square\nspawn\njoin\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(49));
+    Ok(())
+}
+
+#[test]
+fn test_tasks_are_preempted() -> Result<(), EngineError> {
+    use command::Value;
+
+    // The spinner never blocks or yields, so main only gets to set the flag
+    // because the scheduler preempts it.
+    let intput = "func spin\npop\nloop:\nload stop\njf loop\npush 1\nret\nfunc main\npush false\nstore stop\npush 0\npushfn spin\nspawn\npush true\nstore stop\njoin\nend";
+    assert_eq!(eval_source(intput)?, Value::Int(1));
+    Ok(())
+}
+
+#[test]
+fn test_deadlock_is_detected() {
+    let intput = "func main\npush 1\nchannel\nrecv\nend";
+    assert!(matches!(eval_source(intput), Err(EngineError::Deadlock)));
+
+    let intput = "func wait\nload chan\nrecv\nret\nfunc main\npush 1\nchannel\nstore chan\npush 0\npushfn wait\nspawn\ntry failed\njoin\nendtry\nend\nfailed:\nend";
+    assert!(matches!(eval_source(intput), Err(EngineError::Deadlock)));
+
+    assert!(matches!(
+        eval_source("func main\npush 0\nchannel\nend"),
+        Err(EngineError::InvalidCapacity(0))
+    ));
+}
+
+#[test]
+fn test_interrupt_handle() -> Result<(), EngineError> {
+    let program = Parser::new()
+        .parse("func spin\nloop:\njmp loop\nret\nfunc main\ncall spin\npush 0\nend")?;
+    let mut evaluator = Evaluator::new();
+    let handle = evaluator.interrupt_handle();
+
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        handle.interrupt();
+    });
+
+    match evaluator.evaluate(&program) {
+        Err(EngineError::Interrupted { pc, backtrace }) => {
+            assert_eq!(pc, 0);
+            let functions: Vec<_> = backtrace.iter().map(|f| f.function.as_str()).collect();
+            assert_eq!(functions, ["spin", "main"]);
+            assert_eq!(backtrace[1].line, 6);
+        }
+        other => panic!("expected an interrupt, got {:?}", other),
+    }
+
+    // An interrupt during the last few instructions is not lost either.
+    let program = Parser::new().parse("func main\ncall stop\nend")?;
+    let mut evaluator = Evaluator::new();
+    let handle = evaluator.interrupt_handle();
+    evaluator.register("stop", 0, move |_| {
+        handle.interrupt();
+        Ok(command::Value::Nothing)
+    });
+    assert!(matches!(
+        evaluator.evaluate(&program),
+        Err(EngineError::Interrupted { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_timeouts() -> Result<(), EngineError> {
+    use command::Value;
+    use std::time::Instant;
+
+    let program = Parser::new().parse("func main\nloop:\njmp loop\nend")?;
+    let mut evaluator = Evaluator::new();
+    evaluator.set_timeout(Some(Duration::from_millis(20)));
+    assert!(matches!(
+        evaluator.evaluate(&program),
+        Err(EngineError::Timeout { .. })
+    ));
+
+    // A slow native call is not cut short, but the deadline is enforced as
+    // soon as it returns, after its result is pushed and the call is done.
+    let program =
+        Parser::new().parse("func slow\ncall sleep\nret\nfunc main\ncall slow\npush 1\nend")?;
+    let mut evaluator = Evaluator::new();
+    evaluator.register("sleep", 0, |_| {
+        std::thread::sleep(Duration::from_millis(50));
+        Ok(Value::Int(5))
+    });
+    match evaluator.evaluate_until(&program, Instant::now() + Duration::from_millis(10)) {
+        Err(EngineError::Timeout { pc, backtrace }) => {
+            let functions: Vec<_> = backtrace.iter().map(|f| f.function.as_str()).collect();
+            assert_eq!(functions, ["slow", "main"]);
+            assert_eq!(pc, 1);
+            assert_eq!(evaluator.stack(), [Value::Int(5)]);
+        }
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+
+    let mut evaluator = Evaluator::new();
+    evaluator.register("sleep", 0, |_| Ok(Value::Nothing));
+    evaluator.set_timeout(Some(Duration::from_secs(60)));
+    assert_eq!(evaluator.evaluate(&program)?, Value::Int(1));
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_and_resume() -> Result<(), EngineError> {
+    use command::Value;
+
+    let intput = "func producer\nloop:\ndup\nload chan\nsend\npush -1\nadd\ndup\njp loop\nret\nfunc main\npush 2\nchannel\nstore chan\npush 500\npushfn producer\nspawn\nstore task\npush 0\npush 500\nstore left\nnext:\nload chan\nrecv\nadd\nload left\npush -1\nadd\ndup\nstore left\njp next\nload task\njoin\nadd\nend";
+    let program = Parser::new().parse(intput)?;
+
+    let mut evaluator = Evaluator::new();
+    evaluator.interrupt_handle().interrupt();
+    assert!(matches!(
+        evaluator.evaluate(&program),
+        Err(EngineError::Interrupted { .. })
+    ));
+
+    let snapshot = evaluator.snapshot(&program);
+    let mut restored = Evaluator::restore(&program, &snapshot)?;
+    assert_eq!(restored.snapshot(&program), snapshot);
+    assert_eq!(restored.resume(&program)?, Value::Int(125250));
+
+    let other = Parser::new().parse("func main\npush 1\nend")?;
+    assert!(matches!(
+        Evaluator::restore(&other, &snapshot),
+        Err(EngineError::Snapshot(_))
+    ));
+
+    // Handles that point nowhere are rejected when loading, not when used.
+    for (from, to, error) in [
+        ("channel 0", "channel 7", "no channel with handle 7"),
+        ("task 1", "task 9", "no task 9"),
+    ] {
+        assert!(snapshot.contains(from));
+        let corrupt = snapshot.replace(from, to);
+        match Evaluator::restore(&program, &corrupt) {
+            Err(EngineError::Snapshot(message)) => assert!(message.contains(error), "{}", message),
+            other => panic!("expected a snapshot error, got {:?}", other.err()),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_persistent_store() -> Result<(), EngineError> {
+    use command::Value;
+
+    let path = std::env::temp_dir().join(format!("onehour-store-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let counter = "\
+.persist count 10
+func main
+load count
+push 1
+add
+store count
+end";
+    let program = Parser::new().parse(counter)?;
+    let count = || -> Result<Value, EngineError> { Ok(Store::new(&path).load()?["count"].clone()) };
+
+    assert_eq!(Evaluator::new().evaluate(&program)?, Value::Nothing);
+    assert!(!path.exists());
+
+    for expected in [11, 12] {
+        let mut evaluator = Evaluator::new();
+        evaluator.set_store(Some(Store::new(&path)));
+        evaluator.evaluate(&program)?;
+        assert_eq!(count()?, Value::Int(expected));
+    }
+
+    let failing = format!(
+        "{}\nfunc fail\npush 0\npush 1\ndiv\nret",
+        counter.replace("end", "call fail\nend")
+    );
+    let program = Parser::new().parse(&failing)?;
+    let mut evaluator = Evaluator::new();
+    evaluator.set_store(Some(Store::new(&path)));
+    assert!(evaluator.evaluate(&program).is_err());
+    assert_eq!(count()?, Value::Int(12));
+
+    // A nonzero exit code is a failure too, whether it comes from `exit` or
+    // from the result `main` ends with, while a zero one commits.
+    let ends = [
+        ("exit 3", 12),
+        ("push 1\nend", 12),
+        ("push false\nhalt", 12),
+        ("exit 0", 13),
+        ("push 0\nend", 14),
+    ];
+    for (end, expected) in ends {
+        let ending = counter.replace("end", end);
+        let mut evaluator = Evaluator::new();
+        evaluator.set_store(Some(Store::new(&path)));
+        evaluator.evaluate(&Parser::new().parse(&ending)?)?;
+        assert_eq!(count()?, Value::Int(expected));
+    }
+
+    let program =
+        Parser::new().parse(".persist count 0\nfunc main\npush 1\nchannel\nstore count\nend")?;
+    let mut evaluator = Evaluator::new();
+    evaluator.set_store(Some(Store::new(&path)));
+    assert!(matches!(
+        evaluator.evaluate(&program),
+        Err(EngineError::Store(_))
+    ));
+    assert_eq!(count()?, Value::Int(14));
+
+    // Concurrent commits take turns instead of losing each other's updates.
+    let threads: Vec<_> = (0..8)
+        .map(|i| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                Store::new(&path).commit(vec![(format!("v{}", i), Value::Int(i))])
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(Store::new(&path).load()?.len(), 9);
+
+    std::fs::remove_file(&path).unwrap();
+    let mut lock = path.into_os_string();
+    lock.push(".lock");
+    std::fs::remove_file(lock).unwrap();
+    Ok(())
+}
+
+#[test]
+fn test_record_and_replay() -> Result<(), EngineError> {
+    use command::Value;
+    use std::sync::atomic::AtomicI64;
+
+    let script = "\
+func main
+push 10
+call sample
+push 20
+call sample
+add
+try failed
+push 0
+call sample
+endtry
+failed:
+errkind
+pop
+end";
+    let program = Parser::new().parse(script)?;
+
+    let next = AtomicI64::new(1);
+    let mut recorder = Evaluator::new();
+    recorder.register("sample", 1, move |args| match args[0] {
+        Value::Int(0) => Err(EngineError::DivisionByZero),
+        Value::Int(n) => Ok(Value::Int(n * next.fetch_add(1, Ordering::SeqCst))),
+        _ => Err(EngineError::MismatchType),
+    });
+    recorder.record();
+    assert_eq!(recorder.evaluate(&program)?, Value::Int(50));
+    let recording = recorder.recording().unwrap();
+
+    let mut replayer = Evaluator::new();
+    replayer.register("sample", 1, |_| panic!("the host must not be called"));
+    replayer.replay(&recording)?;
+    assert_eq!(replayer.evaluate(&program)?, Value::Int(50));
+
+    for diverging in [
+        script.replace("push 20", "push 21"),
+        script.replace(
+            "try failed\npush 0\ncall sample\nendtry\nfailed:\nerrkind\npop\n",
+            "",
+        ),
+    ] {
+        let mut replayer = Evaluator::new();
+        replayer.register("sample", 1, |_| Ok(Value::Int(0)));
+        replayer.replay(&recording)?;
+        assert!(matches!(
+            replayer.evaluate(&Parser::new().parse(&diverging)?),
+            Err(EngineError::Diverged(_))
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_watch_callbacks() -> Result<(), EngineError> {
+    use command::Value;
+    use std::sync::{Arc, Mutex};
+    use watch::{Action, Watch};
+
+    let program = Parser::new().parse(
+        "func main\npush 0\nstore n\nloop:\nload n\npush 1\nadd\ndup\nstore n\npush -5\nadd\njn loop\nload n\nend",
+    )?;
+
+    let log = Arc::new(Mutex::new(vec![]));
+    let mut evaluator = Evaluator::new();
+    let changes = log.clone();
+    evaluator.watch(Watch::Change("n".into()), move |hit| {
+        changes.lock().unwrap().push(hit.event.to_string());
+        Action::Continue
+    });
+    let pause = evaluator.watch(Watch::Depth(2), |_| Action::Pause);
+
+    assert!(matches!(
+        evaluator.evaluate(&program),
+        Err(EngineError::Paused { watch, .. }) if watch == pause
+    ));
+    assert!(evaluator.unwatch(pause));
+    assert_eq!(evaluator.resume(&program)?, Value::Int(5));
+
+    let log = log.lock().unwrap();
+    assert_eq!(log.len(), 6);
+    assert_eq!(log[0], "`n` set to 0");
+    assert_eq!(log[5], "`n` changed from 4 to 5");
+
+    // Switching to a task with a shallower stack is not a depth change.
+    let program = Parser::new().parse(
+        "func worker\nret\nfunc main\npush 1\npush 2\npush 3\npush 4\npush 0\npushfn worker\nspawn\njoin\nend",
+    )?;
+    let crossings = Arc::new(Mutex::new(0));
+    let mut evaluator = Evaluator::new();
+    let counted = crossings.clone();
+    evaluator.watch(Watch::Depth(2), move |_| {
+        *counted.lock().unwrap() += 1;
+        Action::Continue
+    });
+    assert_eq!(evaluator.evaluate(&program)?, Value::Int(0));
+    assert_eq!(*crossings.lock().unwrap(), 1);
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::command::{EngineError, Value};
+
+pub type NativeFn = dyn Fn(&[Value]) -> Result<Value, EngineError> + Send + Sync;
+
+/// A function implemented in Rust. It receives its arguments in push order
+/// and a `Value::Nothing` result leaves nothing on the stack.
+#[derive(Clone)]
+pub struct Native {
+    pub arity: usize,
+    pub function: Arc<NativeFn>,
+    // Output natives still run when a journal is replayed, so the replay
+    // prints what the recorded run printed.
+    pub output: bool,
+}
+
+impl Native {
+    pub fn new(
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, EngineError> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            arity,
+            function: Arc::new(function),
+            output: false,
+        }
+    }
+}
+
+/// The `print` builtin, writing one line per value to `out`. Strings are
+/// printed without quotes.
+pub fn printer(out: impl Write + Send + 'static) -> Native {
+    let out = Mutex::new(out);
+
+    let mut native = Native::new(1, move |args| {
+        let mut out = out.lock().unwrap_or_else(|e| e.into_inner());
+        let written = match &args[0] {
+            Value::String(s) => writeln!(out, "{}", s),
+            value => writeln!(out, "{}", value),
+        };
+        written.map_err(|e| EngineError::Output(e.to_string()))?;
+        Ok(Value::Nothing)
+    });
+    native.output = true;
+    native
+}
+
+/// `time`: milliseconds since the Unix epoch.
+fn time() -> Native {
+    Native::new(0, |_| {
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Value::Int(elapsed.as_millis() as i64))
+    })
+}
+
+/// `input`: the next line of standard input without its line ending, or
+/// `false` at the end of input.
+fn input() -> Native {
+    Native::new(0, |_| {
+        let mut line = String::new();
+        let read = std::io::stdin()
+            .lock()
+            .read_line(&mut line)
+            .map_err(|e| EngineError::Io(format!("standard input: {}", e)))?;
+        if read == 0 {
+            return Ok(Value::Bool(false));
+        }
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Value::String(line))
+    })
+}
+
+/// `random`: a non-negative integer from a xorshift generator seeded
+/// differently on every run.
+fn random() -> Native {
+    let seed = RandomState::new().build_hasher().finish() | 1;
+    let state = Mutex::new(seed);
+
+    Native::new(0, move |_| {
+        let mut x = state.lock().unwrap_or_else(|e| e.into_inner());
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        Ok(Value::Int((*x >> 1) as i64))
+    })
+}
+
+pub fn builtins() -> HashMap<String, Native> {
+    HashMap::from([
+        ("print".into(), printer(std::io::stdout())),
+        ("time".into(), time()),
+        ("input".into(), input()),
+        ("random".into(), random()),
+    ])
+}
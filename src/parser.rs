@@ -1,14 +1,294 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-use crate::command::{Command, EngineError, Value};
+use crate::bigint::BigInt;
+use crate::command::{Command, EngineError, Overflow, Value};
+use crate::diagnostic::{suggest, Diagnostic};
+use crate::tokenizer::{LexError, Token, TokenKind, Tokenizer};
+
+const MNEMONICS: &[&str] = &[
+    "set",
+    "get",
+    "store",
+    "load",
+    "push",
+    "add",
+    "add.wrap",
+    "add.sat",
+    "sub",
+    "sub.wrap",
+    "sub.sat",
+    "mul",
+    "mul.wrap",
+    "mul.sat",
+    "div",
+    "div.wrap",
+    "div.sat",
+    "mod",
+    "mod.wrap",
+    "mod.sat",
+    "pow",
+    "pow.wrap",
+    "pow.sat",
+    "neg",
+    "neg.wrap",
+    "neg.sat",
+    "abs",
+    "abs.wrap",
+    "abs.sat",
+    "pop",
+    "func",
+    "ret",
+    "end",
+    "halt",
+    "exit",
+    "call",
+    "tailcall",
+    "pushfn",
+    "icall",
+    "closure",
+    "getcap",
+    "setcap",
+    "coroutine",
+    "resume",
+    "yield",
+    "status",
+    "spawn",
+    "join",
+    "channel",
+    "send",
+    "recv",
+    "cmp",
+    "jz",
+    "jp",
+    "jn",
+    "dup",
+    "swap",
+    "over",
+    "rot",
+    "drop",
+    "pick",
+    "eq",
+    "ne",
+    "lt",
+    "le",
+    "gt",
+    "ge",
+    "not",
+    "and",
+    "or",
+    "xor",
+    "shl",
+    "shr",
+    "jmp",
+    "jt",
+    "jf",
+    "try",
+    "endtry",
+    "throw",
+    "errkind",
+    "errmsg",
+    "errline",
+];
+
+const DIRECTIVES: &[&str] = &[".const", ".include", ".global", ".persist"];
 
 pub struct Parser {}
 
+/// A parsed program. It is never modified while running, so one `Program` can
+/// be shared by evaluators on several threads.
 #[derive(Default, Debug)]
 pub struct Program {
     pub commands: Vec<Command>,
     pub functions: HashMap<String, usize>,
     pub labels: HashMap<String, usize>,
+    pub locations: Vec<Location>,
+    pub persistent: Vec<(String, Value)>,
+}
+
+// Fails to compile if a command ever holds something that can't be shared.
+const _: fn() = || {
+    fn assert<T: Send + Sync>() {}
+    assert::<Program>();
+};
+
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+}
+
+impl Program {
+    pub fn location(&self, pc: usize) -> Location {
+        self.locations.get(pc).cloned().unwrap_or_default()
+    }
+
+    /// A fingerprint of the code (FNV-1a over the disassembly), so state saved
+    /// from one program is never resumed against another.
+    pub fn hash(&self) -> u64 {
+        self.to_string()
+            .bytes()
+            .fold(0xcbf29ce484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+            })
+    }
+
+    /// The first instruction on `line` of `file`, if any.
+    pub fn pc_at(&self, file: &str, line: usize) -> Option<usize> {
+        self.locations
+            .iter()
+            .position(|location| location.file == file && location.line == line)
+    }
+
+    /// The name of the function whose body contains `pc`.
+    pub fn function_at(&self, pc: usize) -> &str {
+        self.functions
+            .iter()
+            .filter(|(_, &start)| start <= pc)
+            .max_by_key(|(_, &start)| start)
+            .map_or("?", |(name, _)| name)
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        let mut labels: Vec<_> = self.labels.iter().collect();
+        functions.sort();
+        labels.sort();
+
+        for (pc, command) in self.commands.iter().enumerate() {
+            for (name, _) in functions.iter().filter(|(_, at)| **at == pc) {
+                writeln!(f, "func {}", name)?;
+            }
+            for (name, _) in labels.iter().filter(|(_, at)| **at == pc) {
+                writeln!(f, "{}:", name)?;
+            }
+            writeln!(f, "{:04}    {}", pc, command)?;
+        }
+        for (name, _) in labels.iter().filter(|(_, at)| **at == self.commands.len()) {
+            writeln!(f, "{}:", name)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct ParseState {
+    output: Vec<Command>,
+    locations: Vec<Location>,
+    persistent: Vec<(String, Value)>,
+    functions: HashMap<String, usize>,
+    labels: HashMap<String, usize>,
+    consts: HashMap<String, Value>,
+    globals: Vec<(String, Diagnostic)>,
+    function: Option<String>,
+    label_defs: Vec<LabelDef>,
+    label_refs: Vec<LabelRef>,
+    includes: Vec<PathBuf>,
+    definitions: HashMap<String, String>,
+    diagnostics: Vec<Diagnostic>,
+    file: String,
+    line: usize,
+    source: String,
+}
+
+impl ParseState {
+    fn emit(&mut self, command: Command) {
+        self.output.push(command);
+        self.locations.push(Location {
+            file: self.file.clone(),
+            line: self.line,
+        });
+    }
+
+    fn location(&self) -> String {
+        format!("{}:{}", self.file, self.line)
+    }
+
+    fn diagnostic(&self, error: ParseError) -> Diagnostic {
+        Diagnostic {
+            file: self.file.clone(),
+            line: self.line,
+            column: error.column,
+            width: error.width,
+            message: error.message,
+            source: self.source.clone(),
+            help: error.help,
+        }
+    }
+
+    fn define(&mut self, kind: &str, name: &str, at: &Token) -> Result<(), ParseError> {
+        let key = format!("{} {}", kind, name);
+
+        if let Some(previous) = self.definitions.get(&key) {
+            return Err(ParseError::new(
+                at,
+                EngineError::DuplicateDefinition(format!("{} `{}`", kind, name)),
+            )
+            .with_help(format!("previous definition at {}", previous)));
+        }
+
+        self.definitions.insert(key, self.location());
+        Ok(())
+    }
+}
+
+struct LabelDef {
+    function: Option<String>,
+    name: String,
+    address: usize,
+    at: Diagnostic,
+}
+
+struct LabelRef {
+    command: usize,
+    function: Option<String>,
+    name: String,
+    at: Diagnostic,
+}
+
+fn qualify(function: Option<&str>, label: &str) -> String {
+    match function {
+        Some(function) => format!("{}.{}", function, label),
+        None => label.into(),
+    }
+}
+
+struct ParseError {
+    message: String,
+    column: usize,
+    width: usize,
+    help: Option<String>,
+}
+
+impl ParseError {
+    fn new(token: &Token, error: EngineError) -> Self {
+        Self {
+            message: error.to_string(),
+            column: token.column,
+            width: token.width,
+            help: None,
+        }
+    }
+
+    fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        Self {
+            message: e.error.to_string(),
+            column: e.column,
+            width: 1,
+            help: None,
+        }
+    }
 }
 
 impl Parser {
@@ -16,137 +296,550 @@ impl Parser {
         Self {}
     }
 
-    fn parse_var_name(&self, input: &str) -> Result<String, EngineError> {
-        Ok(input.into())
+    fn operands<'t>(&self, input: &'t [Token], names: &[&str]) -> Result<&'t [Token], ParseError> {
+        let mnemonic = &input[0];
+        let operands = &input[1..];
+
+        if operands.len() < names.len() {
+            let last = &input[input.len() - 1];
+            let missing = names[operands.len()];
+            return Err(ParseError {
+                message: format!(
+                    "`{}` is missing its {} operand",
+                    describe(mnemonic),
+                    missing
+                ),
+                column: last.column + last.width + 1,
+                width: 1,
+                help: Some(format!(
+                    "expected `{} {}`",
+                    describe(mnemonic),
+                    names
+                        .iter()
+                        .map(|name| format!("<{}>", name))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )),
+            });
+        }
+
+        if operands.len() > names.len() {
+            let extra = &operands[names.len()];
+            let last = &input[input.len() - 1];
+            return Err(ParseError {
+                message: format!("unexpected operand for `{}`", describe(mnemonic)),
+                column: extra.column,
+                width: last.column + last.width - extra.column,
+                help: None,
+            });
+        }
+
+        Ok(operands)
+    }
+
+    fn parse_var_name(&self, input: &Token) -> Result<String, ParseError> {
+        match input.word() {
+            Some(name) => Ok(name.into()),
+            None => Err(ParseError::new(input, EngineError::MismatchType)
+                .with_help("expected a name, found a string literal")),
+        }
     }
 
-    fn parse_int(&self, input: &str) -> Result<Value, EngineError> {
-        let result = input.parse::<i64>();
+    fn parse_int(&self, input: &Token, text: &str) -> Result<Value, ParseError> {
+        if let Some(digits) = text.strip_suffix('n') {
+            return match BigInt::parse(digits) {
+                Some(n) => Ok(Value::from(n)),
+                None => Err(ParseError::new(input, EngineError::MismatchType)
+                    .with_help("expected decimal digits before the `n` suffix")),
+            };
+        }
+
+        let result = text.parse::<i64>();
         match result {
             Ok(x) => Ok(Value::Int(x)),
-            Err(_) => Err(EngineError::MismatchType),
+            Err(_) if BigInt::parse(text).is_some() => {
+                Err(
+                    ParseError::new(input, EngineError::Overflow).with_help(format!(
+                        "use `{}n` for an arbitrary-precision integer literal",
+                        text
+                    )),
+                )
+            }
+            Err(_) => Err(ParseError::new(input, EngineError::MismatchType)
+                .with_help("expected an integer, a boolean, a string literal or a constant")),
+        }
+    }
+
+    fn parse_value(&self, state: &ParseState, input: &Token) -> Result<Value, ParseError> {
+        match &input.kind {
+            TokenKind::Str(s) => Ok(Value::String(s.clone())),
+            TokenKind::Word(w) => match state.consts.get(w) {
+                Some(value) => Ok(value.clone()),
+                None if w == "true" => Ok(Value::Bool(true)),
+                None if w == "false" => Ok(Value::Bool(false)),
+                None => self.parse_int(input, w),
+            },
         }
     }
 
-    fn parse_string(&self, input: &str) -> Result<Value, EngineError> {
-        if input.starts_with('\"') && input.ends_with('\"') && input.len() > 1 {
-            let inner = input[1..(input.len() - 1)].to_string();
+    fn parse_set(&self, state: &ParseState, input: &[Token]) -> Result<Command, ParseError> {
+        let operands = self.operands(input, &["variable", "value"])?;
 
-            Ok(Value::String(inner))
-        } else {
-            Err(EngineError::MismatchType)
+        let var_name = self.parse_var_name(&operands[0])?;
+        let value = self.parse_value(state, &operands[1])?;
+
+        Ok(Command::SetVar(var_name, value))
+    }
+
+    fn parse_get(&self, input: &[Token]) -> Result<Command, ParseError> {
+        Ok(Command::GetVar(self.parse_var_operand(input)?))
+    }
+
+    fn parse_var_operand(&self, input: &[Token]) -> Result<String, ParseError> {
+        let operands = self.operands(input, &["variable"])?;
+
+        self.parse_var_name(&operands[0])
+    }
+
+    fn parse_push(&self, state: &ParseState, input: &[Token]) -> Result<Command, ParseError> {
+        let operands = self.operands(input, &["value"])?;
+
+        let var_name = self.parse_value(state, &operands[0])?;
+
+        Ok(Command::Push(var_name))
+    }
+
+    fn parse_count(
+        &self,
+        state: &ParseState,
+        token: &Token,
+        what: &str,
+    ) -> Result<usize, ParseError> {
+        match self.parse_value(state, token)? {
+            Value::Int(n) if n >= 0 => Ok(n as usize),
+            _ => Err(ParseError::new(token, EngineError::MismatchType)
+                .with_help(format!("expected a non-negative {}", what))),
         }
     }
 
-    fn parse_value(&self, input: &str) -> Result<Value, EngineError> {
-        if input.starts_with('\"') && input.ends_with('"') && input.len() > 1 {
-            self.parse_string(input)
-        } else {
-            self.parse_int(input)
+    fn parse_pick(&self, state: &ParseState, input: &[Token]) -> Result<Command, ParseError> {
+        let operands = self.operands(input, &["depth"])?;
+
+        Ok(Command::Pick(self.parse_count(
+            state,
+            &operands[0],
+            "stack depth",
+        )?))
+    }
+
+    fn parse_closure(&self, state: &ParseState, input: &[Token]) -> Result<Command, ParseError> {
+        let operands = self.operands(input, &["function", "captures"])?;
+
+        Ok(Command::Closure(
+            self.parse_var_name(&operands[0])?,
+            self.parse_count(state, &operands[1], "capture count")?,
+        ))
+    }
+
+    fn parse_slot(&self, state: &ParseState, input: &[Token]) -> Result<usize, ParseError> {
+        let operands = self.operands(input, &["slot"])?;
+
+        self.parse_count(state, &operands[0], "capture slot")
+    }
+
+    fn parse_exit(&self, state: &ParseState, input: &[Token]) -> Result<Command, ParseError> {
+        if input.len() == 1 {
+            return Ok(Command::Exit(None));
+        }
+
+        let operands = self.operands(input, &["code"])?;
+
+        match self.parse_value(state, &operands[0])? {
+            Value::Int(code) => Ok(Command::Exit(Some(code))),
+            _ => Err(ParseError::new(&operands[0], EngineError::MismatchType)
+                .with_help("exit codes are integers")),
         }
     }
 
-    fn parse_set(&self, input: &[&str]) -> Result<Command, EngineError> {
-        if input.len() != 3 {
-            return Err(EngineError::MismatchNumParams);
+    fn parse_func_operand(&self, input: &[Token]) -> Result<String, ParseError> {
+        let operands = self.operands(input, &["function"])?;
+
+        self.parse_var_name(&operands[0])
+    }
+
+    fn parse_label_operand(
+        &self,
+        state: &mut ParseState,
+        input: &[Token],
+    ) -> Result<String, ParseError> {
+        let operands = self.operands(input, &["label"])?;
+
+        let name = self.parse_var_name(&operands[0])?;
+        let at = state.diagnostic(ParseError::new(
+            &operands[0],
+            EngineError::UnknownLabel(name.clone()),
+        ));
+        state.label_refs.push(LabelRef {
+            command: state.output.len(),
+            function: state.function.clone(),
+            name: name.clone(),
+            at,
+        });
+
+        Ok(name)
+    }
+
+    fn parse_func(&self, state: &mut ParseState, input: &[Token]) -> Result<(), ParseError> {
+        let operands = self.operands(input, &["name"])?;
+
+        let name = self.parse_var_name(&operands[0])?;
+        state.define("function", &name, &operands[0])?;
+        state.functions.insert(name.clone(), state.output.len());
+        state.function = Some(name);
+
+        Ok(())
+    }
+
+    fn parse_label(&self, state: &mut ParseState, token: &Token) -> Result<(), ParseError> {
+        let Some(label) = token.word().and_then(|x| x.strip_suffix(':')) else {
+            return Err(unknown_command(token));
+        };
+        if label.is_empty() || label.contains(':') {
+            return Err(unknown_command(token));
         }
+        let qualified = qualify(state.function.as_deref(), label);
+        state.define("label", &qualified, token)?;
+        let at = state.diagnostic(ParseError::new(
+            token,
+            EngineError::DuplicateDefinition(format!("global label `{}`", label)),
+        ));
+        state.label_defs.push(LabelDef {
+            function: state.function.clone(),
+            name: label.into(),
+            address: state.output.len(),
+            at,
+        });
 
-        let var_name = self.parse_var_name(input[1])?;
-        let value = self.parse_value(input[2])?;
+        Ok(())
+    }
 
-        Ok(Command::SetVar(var_name, value))
+    fn parse_const(&self, state: &mut ParseState, input: &[Token]) -> Result<(), ParseError> {
+        let operands = self.operands(input, &["name", "value"])?;
+
+        let name = self.parse_var_name(&operands[0])?;
+        let value = self.parse_value(state, &operands[1])?;
+
+        state.define("constant", &name, &operands[0])?;
+        state.consts.insert(name, value);
+
+        Ok(())
+    }
+
+    fn parse_persist(&self, state: &mut ParseState, input: &[Token]) -> Result<(), ParseError> {
+        let operands = self.operands(input, &["name", "initial value"])?;
+
+        let name = self.parse_var_name(&operands[0])?;
+        let value = self.parse_value(state, &operands[1])?;
+
+        state.define("persistent variable", &name, &operands[0])?;
+        state.persistent.push((name, value));
+
+        Ok(())
+    }
+
+    fn parse_include(
+        &self,
+        state: &mut ParseState,
+        base: &Path,
+        input: &[Token],
+    ) -> Result<(), ParseError> {
+        let operands = self.operands(input, &["file"])?;
+
+        let TokenKind::Str(file) = &operands[0].kind else {
+            return Err(ParseError::new(&operands[0], EngineError::MismatchType)
+                .with_help("the file name must be a string literal"));
+        };
+
+        self.parse_source_file(state, &base.join(file))
+            .map_err(|e| ParseError::new(&operands[0], EngineError::Include(e)))
     }
 
-    fn parse_get(&self, input: &[&str]) -> Result<Command, EngineError> {
-        if input.len() != 2 {
-            return Err(EngineError::MismatchNumParams);
+    fn parse_global(&self, state: &mut ParseState, input: &[Token]) -> Result<(), ParseError> {
+        if input.len() < 2 {
+            self.operands(input, &["name"])?;
         }
 
-        let var_name = self.parse_var_name(input[1])?;
+        for name in &input[1..] {
+            let symbol = self.parse_var_name(name)?;
+            let pending = state.diagnostic(ParseError::new(
+                name,
+                EngineError::UnknownSymbol(symbol.clone()),
+            ));
+            state.globals.push((symbol, pending));
+        }
 
-        Ok(Command::GetVar(var_name))
+        Ok(())
     }
 
-    fn parse_push(&self, input: &[&str]) -> Result<Command, EngineError> {
-        if input.len() != 2 {
-            return Err(EngineError::MismatchNumParams);
+    fn parse_directive(
+        &self,
+        state: &mut ParseState,
+        base: &Path,
+        input: &[Token],
+    ) -> Result<(), ParseError> {
+        match input[0].word() {
+            Some(".const") => self.parse_const(state, input),
+            Some(".include") => self.parse_include(state, base, input),
+            Some(".global") => self.parse_global(state, input),
+            Some(".persist") => self.parse_persist(state, input),
+            Some(name) => {
+                let error =
+                    ParseError::new(&input[0], EngineError::UnknownDirective(name.to_string()));
+                match suggest(name, DIRECTIVES.iter().copied()) {
+                    Some(known) => Err(error.with_help(format!("did you mean `{}`?", known))),
+                    None => Err(error),
+                }
+            }
+            None => unreachable!(),
         }
+    }
 
-        let var_name = self.parse_value(input[1])?;
+    fn parse_line(
+        &self,
+        state: &mut ParseState,
+        base: &Path,
+        line: &str,
+    ) -> Result<(), ParseError> {
+        let mut command = Tokenizer::new(line).tokenize()?;
 
-        Ok(Command::Push(var_name))
+        if command
+            .first()
+            .and_then(Token::word)
+            .is_some_and(|x| x.contains(':'))
+        {
+            let label = command.remove(0);
+            self.parse_label(state, &label)?;
+        }
+
+        let Some(first) = command.first() else {
+            return Ok(());
+        };
+        let Some(x) = first.word() else {
+            return Err(unknown_command(first));
+        };
+
+        if let Some(op) = arithmetic(x) {
+            self.operands(&command, &[])?;
+            state.emit(op);
+            return Ok(());
+        }
+
+        let command = match x {
+            x if x.starts_with('.') => return self.parse_directive(state, base, &command),
+            "func" => return self.parse_func(state, &command),
+            "set" => self.parse_set(state, &command)?,
+            "get" => self.parse_get(&command)?,
+            "store" => Command::Store(self.parse_var_operand(&command)?),
+            "load" => Command::Load(self.parse_var_operand(&command)?),
+            "push" => self.parse_push(state, &command)?,
+            "pop" => self.operands(&command, &[]).map(|_| Command::Pop)?,
+            "ret" => self.operands(&command, &[]).map(|_| Command::Ret)?,
+            "end" => self.operands(&command, &[]).map(|_| Command::End)?,
+            "halt" => self.operands(&command, &[]).map(|_| Command::Halt)?,
+            "exit" => self.parse_exit(state, &command)?,
+            "call" => Command::FuncCall(self.parse_func_operand(&command)?),
+            "tailcall" => Command::TailCall(self.parse_func_operand(&command)?),
+            "pushfn" => Command::PushFn(self.parse_func_operand(&command)?),
+            "icall" => self.operands(&command, &[]).map(|_| Command::ICall)?,
+            "closure" => self.parse_closure(state, &command)?,
+            "getcap" => Command::GetCap(self.parse_slot(state, &command)?),
+            "setcap" => Command::SetCap(self.parse_slot(state, &command)?),
+            "coroutine" => self.operands(&command, &[]).map(|_| Command::Coroutine)?,
+            "resume" => self.operands(&command, &[]).map(|_| Command::Resume)?,
+            "yield" => self.operands(&command, &[]).map(|_| Command::Yield)?,
+            "status" => self.operands(&command, &[]).map(|_| Command::Status)?,
+            "spawn" => self.operands(&command, &[]).map(|_| Command::Spawn)?,
+            "join" => self.operands(&command, &[]).map(|_| Command::Join)?,
+            "channel" => self.operands(&command, &[]).map(|_| Command::Channel)?,
+            "send" => self.operands(&command, &[]).map(|_| Command::Send)?,
+            "recv" => self.operands(&command, &[]).map(|_| Command::Recv)?,
+            "cmp" => self.operands(&command, &[]).map(|_| Command::Cmp)?,
+            "jz" => Command::Jz(self.parse_label_operand(state, &command)?),
+            "jp" => Command::Jp(self.parse_label_operand(state, &command)?),
+            "jn" => Command::Jn(self.parse_label_operand(state, &command)?),
+            "dup" => self.operands(&command, &[]).map(|_| Command::Dup)?,
+            "swap" => self.operands(&command, &[]).map(|_| Command::Swap)?,
+            "over" => self.operands(&command, &[]).map(|_| Command::Over)?,
+            "rot" => self.operands(&command, &[]).map(|_| Command::Rot)?,
+            "drop" => self.operands(&command, &[]).map(|_| Command::Drop)?,
+            "pick" => self.parse_pick(state, &command)?,
+            "eq" => self.operands(&command, &[]).map(|_| Command::Eq)?,
+            "ne" => self.operands(&command, &[]).map(|_| Command::Ne)?,
+            "lt" => self.operands(&command, &[]).map(|_| Command::Lt)?,
+            "le" => self.operands(&command, &[]).map(|_| Command::Le)?,
+            "gt" => self.operands(&command, &[]).map(|_| Command::Gt)?,
+            "ge" => self.operands(&command, &[]).map(|_| Command::Ge)?,
+            "not" => self.operands(&command, &[]).map(|_| Command::Not)?,
+            "and" => self.operands(&command, &[]).map(|_| Command::And)?,
+            "or" => self.operands(&command, &[]).map(|_| Command::Or)?,
+            "xor" => self.operands(&command, &[]).map(|_| Command::Xor)?,
+            "shl" => self.operands(&command, &[]).map(|_| Command::Shl)?,
+            "shr" => self.operands(&command, &[]).map(|_| Command::Shr)?,
+            "jmp" => Command::Jmp(self.parse_label_operand(state, &command)?),
+            "jt" => Command::Jt(self.parse_label_operand(state, &command)?),
+            "jf" => Command::Jf(self.parse_label_operand(state, &command)?),
+            "try" => Command::Try(self.parse_label_operand(state, &command)?),
+            "endtry" => self.operands(&command, &[]).map(|_| Command::EndTry)?,
+            "throw" => self.operands(&command, &[]).map(|_| Command::Throw)?,
+            "errkind" => self.operands(&command, &[]).map(|_| Command::ErrKind)?,
+            "errmsg" => self.operands(&command, &[]).map(|_| Command::ErrMsg)?,
+            "errline" => self.operands(&command, &[]).map(|_| Command::ErrLine)?,
+            _ => return Err(unknown_command(first)),
+        };
+
+        state.emit(command);
+        Ok(())
     }
 
-    fn parse_func_call(&self, input: &[&str]) -> Result<Command, EngineError> {
-        if input.len() != 2 {
-            return Err(EngineError::MismatchNumParams);
+    fn parse_source_file(&self, state: &mut ParseState, path: &Path) -> Result<(), String> {
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        if state.includes.contains(&canonical) {
+            return Err(format!("{}: recursive include", path.display()));
         }
 
-        Ok(Command::FuncCall(input[1].into()))
+        let contents = std::fs::read_to_string(&canonical)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        state.includes.push(canonical);
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        self.parse_source(state, base, &path.display().to_string(), &contents);
+        state.includes.pop();
+
+        Ok(())
     }
 
-    pub fn parse(&self, input: &str) -> Result<Program, EngineError> {
-        let mut output = vec![];
-        let mut functions: HashMap<String, usize> = HashMap::new();
+    fn parse_source(&self, state: &mut ParseState, base: &Path, file: &str, input: &str) {
+        let outer = (
+            std::mem::replace(&mut state.file, file.into()),
+            state.line,
+            std::mem::take(&mut state.source),
+        );
 
-        let mut labels: HashMap<String, usize> = HashMap::new();
+        for (index, line) in input.lines().enumerate() {
+            state.line = index + 1;
+            state.source = line.into();
 
-        for line in input.lines() {
-            let command: Vec<_> = line.split_ascii_whitespace().collect();
+            if let Err(e) = self.parse_line(state, base, line) {
+                let diagnostic = state.diagnostic(e);
+                state.diagnostics.push(diagnostic);
+            }
+        }
 
-            match command.get(0) {
-                Some(x) if x.contains(':') => {
-                    if let Some(label) = x.strip_suffix(':') {
-                        labels.insert(label.into(), output.len());
-                    } else {
-                        return Err(EngineError::UnknownCommand(x.to_string()));
-                    }
-                }
-                Some(x) if *x == "set" => {
-                    output.push(self.parse_set(&command)?);
-                }
-                Some(x) if *x == "get" => {
-                    output.push(self.parse_get(&command)?);
-                }
-                Some(x) if *x == "push" => {
-                    output.push(self.parse_push(&command)?);
-                }
-                Some(x) if *x == "pop" => {
-                    output.push(Command::Pop);
-                }
-                Some(x) if *x == "add" => {
-                    output.push(Command::Add);
-                }
-                Some(x) if *x == "mul" => {
-                    output.push(Command::Mul);
-                }
-                Some(x) if *x == "sub" => {
-                    output.push(Command::Sub);
-                }
-                Some(x) if *x == "div" => {
-                    output.push(Command::Div);
-                }
-                Some(x) if *x == "func" => {
-                    functions.insert(command[1].into(), output.len());
-                }
-                Some(x) if *x == "ret" => output.push(Command::Ret),
-                Some(x) if *x == "end" => output.push(Command::End),
-                Some(x) if *x == "call" => output.push(self.parse_func_call(&command)?),
-                Some(x) if *x == "cmp" => output.push(Command::Cmp),
-                Some(x) if *x == "jz" => output.push(Command::Jz(command[1].into())),
-                Some(x) if *x == "jp" => output.push(Command::Jp(command[1].into())),
-                Some(x) if *x == "jn" => output.push(Command::Jn(command[1].into())),
-                Some(name) => return Err(EngineError::UnknownCommand(name.to_string())),
-                None => {}
+        (state.file, state.line, state.source) = outer;
+    }
+
+    fn resolve_labels(&self, state: &mut ParseState) {
+        let globals: Vec<String> = state.globals.iter().map(|(name, _)| name.clone()).collect();
+
+        for def in std::mem::take(&mut state.label_defs) {
+            let key = if globals.contains(&def.name) {
+                def.name.clone()
+            } else {
+                qualify(def.function.as_deref(), &def.name)
+            };
+
+            if state.labels.insert(key, def.address).is_some() {
+                state.diagnostics.push(def.at);
+            } else {
+                state.label_defs.push(def);
             }
         }
 
+        for r in std::mem::take(&mut state.label_refs) {
+            let local = qualify(r.function.as_deref(), &r.name);
+            let key = if state.labels.contains_key(&local) {
+                local
+            } else if globals.contains(&r.name) && state.labels.contains_key(&r.name) {
+                r.name.clone()
+            } else {
+                state.diagnostics.push(self.unknown_label(state, r));
+                continue;
+            };
+
+            if let Some(label) = state.output[r.command].label_mut() {
+                *label = key;
+            }
+        }
+    }
+
+    fn unknown_label(&self, state: &ParseState, r: LabelRef) -> Diagnostic {
+        let mut at = r.at;
+
+        let owners: Vec<_> = state
+            .label_defs
+            .iter()
+            .filter(|def| def.name == r.name)
+            .filter_map(|def| def.function.as_deref())
+            .collect();
+
+        if let Some(owner) = owners.first() {
+            at.help = Some(format!(
+                "`{}` belongs to function `{}`; jumps cannot cross function boundaries unless the label is declared with `.global {}`",
+                r.name, owner, r.name
+            ));
+        } else {
+            let scope = r.function.as_deref();
+            let candidates = state
+                .label_defs
+                .iter()
+                .filter(|def| def.function.as_deref() == scope)
+                .map(|def| def.name.as_str());
+            if let Some(known) = suggest(&r.name, candidates) {
+                at.help = Some(format!("did you mean `{}`?", known));
+            }
+        }
+
+        at
+    }
+
+    fn finish(&self, mut state: ParseState) -> Result<Program, EngineError> {
+        self.resolve_labels(&mut state);
+
+        for (name, pending) in std::mem::take(&mut state.globals) {
+            let is_label = state.label_defs.iter().any(|def| def.name == name);
+            if !state.functions.contains_key(&name) && !is_label {
+                state.diagnostics.push(pending);
+            }
+        }
+
+        if !state.diagnostics.is_empty() {
+            return Err(EngineError::Syntax(state.diagnostics));
+        }
+
         Ok(Program {
-            commands: output,
-            functions,
-            labels,
+            commands: state.output,
+            functions: state.functions,
+            labels: state.labels,
+            locations: state.locations,
+            persistent: state.persistent,
         })
     }
+
+    pub fn parse(&self, input: &str) -> Result<Program, EngineError> {
+        let mut state = ParseState::default();
+        self.parse_source(&mut state, Path::new(""), "<input>", input);
+        self.finish(state)
+    }
+
+    pub fn parse_file(&self, path: impl AsRef<Path>) -> Result<Program, EngineError> {
+        let mut state = ParseState::default();
+        self.parse_source_file(&mut state, path.as_ref())
+            .map_err(EngineError::Io)?;
+        self.finish(state)
+    }
 }
 
 impl Default for Parser {
@@ -154,3 +847,43 @@ impl Default for Parser {
         Self::new()
     }
 }
+
+fn arithmetic(mnemonic: &str) -> Option<Command> {
+    let (name, mode) = match mnemonic.split_once('.') {
+        None => (mnemonic, Overflow::Checked),
+        Some((name, "wrap")) => (name, Overflow::Wrapping),
+        Some((name, "sat")) => (name, Overflow::Saturating),
+        Some(_) => return None,
+    };
+
+    let op: fn(Overflow) -> Command = match name {
+        "add" => Command::Add,
+        "sub" => Command::Sub,
+        "mul" => Command::Mul,
+        "div" => Command::Div,
+        "mod" => Command::Mod,
+        "pow" => Command::Pow,
+        "neg" => Command::Neg,
+        "abs" => Command::Abs,
+        _ => return None,
+    };
+
+    Some(op(mode))
+}
+
+fn describe(token: &Token) -> String {
+    match &token.kind {
+        TokenKind::Word(w) => w.clone(),
+        TokenKind::Str(s) => Value::String(s.clone()).to_string(),
+    }
+}
+
+fn unknown_command(token: &Token) -> ParseError {
+    let name = describe(token);
+    let error = ParseError::new(token, EngineError::UnknownCommand(name.clone()));
+
+    match suggest(&name, MNEMONICS.iter().copied()) {
+        Some(known) => error.with_help(format!("did you mean `{}`?", known)),
+        None => error,
+    }
+}
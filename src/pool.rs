@@ -0,0 +1,169 @@
+use std::io::Write;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::command::{EngineError, Value};
+use crate::eval::Evaluator;
+use crate::parser::Program;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// What running one program in its own evaluator produced.
+#[derive(Debug)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub result: Result<Value, EngineError>,
+}
+
+/// Output collected in memory, shared between the writer handed to an
+/// evaluator and whoever reads it back.
+#[derive(Clone, Default)]
+pub struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Buffer {
+    /// Everything written since the last call.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `program` in `evaluator`, collecting what it prints instead of
+/// writing it to standard output.
+pub fn isolate(mut evaluator: Evaluator, program: &Program) -> Outcome {
+    let buffer = Buffer::default();
+    evaluator.set_output(buffer.clone());
+
+    let result = evaluator.evaluate(program);
+    let output = buffer.take();
+    Outcome { output, result }
+}
+
+/// The result of a job submitted to a `Pool`.
+pub struct Pending<T>(Receiver<T>);
+
+impl<T> Pending<T> {
+    /// Blocks until the job has finished. Panics if the job panicked.
+    pub fn wait(self) -> T {
+        self.0.recv().expect("pool job panicked")
+    }
+}
+
+/// A fixed set of worker threads, each running one job at a time.
+pub struct Pool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Pool {
+    pub fn new(threads: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                std::thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    pub fn spawn<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> Pending<T> {
+        let (sender, receiver) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            sender.send(job()).ok();
+        });
+
+        self.sender
+            .as_ref()
+            .expect("pool is running")
+            .send(job)
+            .expect("pool workers have stopped");
+        Pending(receiver)
+    }
+
+    pub fn evaluate(&self, program: Arc<Program>) -> Pending<Outcome> {
+        self.spawn(move || isolate(Evaluator::new(), &program))
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.sender.take();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+#[test]
+fn test_pool_runs_programs_concurrently() -> Result<(), EngineError> {
+    use crate::parser::Parser;
+
+    let program = Arc::new(Parser::new().parse(
+        "func main\npush \"hi\"\ncall print\npush 1000\nloop:\npush -1\nadd\ndup\njp loop\nend",
+    )?);
+    let pool = Pool::new(4);
+
+    let pending: Vec<_> = (0..16)
+        .map(|_| pool.evaluate(Arc::clone(&program)))
+        .collect();
+    for pending in pending {
+        let outcome = pending.wait();
+        assert_eq!(outcome.output, b"hi\n");
+        assert_eq!(outcome.result?, Value::Int(0));
+    }
+
+    // Each job waits inside a native until all four have arrived, which
+    // only happens if the pool really runs them at the same time.
+    let program = Arc::new(Parser::new().parse("func main\ncall meet\nend")?);
+    let arrived = Arc::new((Mutex::new(0), std::sync::Condvar::new()));
+    let pending: Vec<_> = (0..4)
+        .map(|_| {
+            let program = Arc::clone(&program);
+            let arrived = Arc::clone(&arrived);
+            pool.spawn(move || {
+                let mut evaluator = Evaluator::new();
+                evaluator.register("meet", 0, move |_| {
+                    let (count, all) = &*arrived;
+                    let mut count = count.lock().unwrap();
+                    *count += 1;
+                    all.notify_all();
+                    let timeout = std::time::Duration::from_secs(10);
+                    let (count, _) = all.wait_timeout_while(count, timeout, |n| *n < 4).unwrap();
+                    Ok(Value::Bool(*count == 4))
+                });
+                isolate(evaluator, &program)
+            })
+        })
+        .collect();
+    for pending in pending {
+        assert_eq!(pending.wait().result?, Value::Bool(true));
+    }
+    Ok(())
+}
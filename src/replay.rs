@@ -0,0 +1,163 @@
+use crate::command::{EngineError, ErrorValue, Value};
+use crate::snapshot::{invalid, Reader, Writer};
+
+const JOURNAL: &str = "onehour-journal";
+
+/// One native call as the program made it, and what it returned.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    pub name: String,
+    pub args: Vec<Value>,
+    pub result: Result<Value, ErrorValue>,
+}
+
+/// The native calls of a run, either being recorded or being fed back in
+/// the order they were made.
+#[derive(Debug)]
+pub enum Journal {
+    Record(Vec<Call>),
+    Replay { calls: Vec<Call>, next: usize },
+}
+
+impl Journal {
+    pub fn load(input: &str) -> Result<Self, EngineError> {
+        let mut input = Reader::new(input, JOURNAL)?;
+        let calls = input.list(|input| {
+            let name = input.string()?;
+            let args = input.values()?;
+            let result = match input.word()?.as_str() {
+                "ok" => Ok(input.value()?),
+                "err" => match input.value()? {
+                    Value::Error(e) => Err(*e),
+                    _ => return Err(invalid("expected an error value")),
+                },
+                word => return Err(invalid(format!("unknown result `{}`", word))),
+            };
+            Ok(Call { name, args, result })
+        })?;
+        input.finish()?;
+
+        Ok(Journal::Replay { calls, next: 0 })
+    }
+
+    pub fn save(&self) -> String {
+        let calls = match self {
+            Journal::Record(calls) | Journal::Replay { calls, .. } => calls,
+        };
+
+        let mut out = Writer::new(JOURNAL);
+        out.line();
+        out.list(calls, |out, call| {
+            out.string(&call.name);
+            out.values(&call.args);
+            match &call.result {
+                Ok(value) => {
+                    out.word("ok");
+                    out.value(value);
+                }
+                Err(e) => {
+                    out.word("err");
+                    out.value(&Value::Error(Box::new(e.clone())));
+                }
+            }
+            out.line();
+        });
+        out.finish()
+    }
+
+    /// The recorded result of the call the program is making now. Fails if
+    /// the log expected a different call.
+    pub fn replay(
+        &mut self,
+        name: &str,
+        args: &[Value],
+    ) -> Result<Result<Value, ErrorValue>, EngineError> {
+        let Journal::Replay { calls, next } = self else {
+            unreachable!("replaying a journal that is being recorded");
+        };
+
+        let Some(call) = calls.get(*next) else {
+            return Err(EngineError::Diverged(format!(
+                "call {} to `{}` was never recorded",
+                *next + 1,
+                name
+            )));
+        };
+        if call.name != name || call.args != args {
+            return Err(EngineError::Diverged(format!(
+                "call {} is `{}` with {}, but `{}` with {} was recorded",
+                *next + 1,
+                name,
+                describe(args),
+                call.name,
+                describe(&call.args)
+            )));
+        }
+
+        *next += 1;
+        Ok(call.result.clone())
+    }
+
+    /// Fails if a replayed run ended before making every recorded call.
+    pub fn finish(&self) -> Result<(), EngineError> {
+        match self {
+            Journal::Replay { calls, next } if *next < calls.len() => {
+                Err(EngineError::Diverged(format!(
+                    "the program finished after {} of {} recorded calls",
+                    next,
+                    calls.len()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn describe(args: &[Value]) -> String {
+    match args {
+        [] => "no arguments".into(),
+        args => {
+            let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+            format!("({})", args.join(", "))
+        }
+    }
+}
+
+#[test]
+fn test_journal_roundtrip() -> Result<(), EngineError> {
+    let calls = vec![
+        Call {
+            name: "time".into(),
+            args: vec![],
+            result: Ok(Value::Int(1700000000000)),
+        },
+        Call {
+            name: "lookup".into(),
+            args: vec![Value::String("key \"1\"".into()), Value::Bool(true)],
+            result: Err(ErrorValue {
+                kind: "thrown".into(),
+                message: "missing".into(),
+                file: "main.onehour".into(),
+                line: 3,
+            }),
+        },
+    ];
+
+    let saved = Journal::Record(calls.clone()).save();
+    let mut journal = Journal::load(&saved)?;
+    assert_eq!(journal.save(), saved);
+
+    assert_eq!(journal.replay("time", &[])?, calls[0].result);
+    assert!(matches!(journal.finish(), Err(EngineError::Diverged(_))));
+    assert!(matches!(
+        journal.replay("lookup", &[Value::Bool(true)]),
+        Err(EngineError::Diverged(_))
+    ));
+    assert_eq!(journal.replay("lookup", &calls[1].args)?, calls[1].result);
+    assert!(journal.finish().is_ok());
+    assert!(matches!(
+        journal.replay("time", &[]),
+        Err(EngineError::Diverged(_))
+    ));
+    Ok(())
+}
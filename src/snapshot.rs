@@ -0,0 +1,256 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::bigint::BigInt;
+use crate::command::{EngineError, ErrorValue, Value};
+use crate::tokenizer::{escape, TokenKind, Tokenizer};
+
+const VERSION: u32 = 1;
+
+// Snapshots and the persistent store are text: whitespace separated words
+// and quoted strings, read back with the assembly tokenizer. Lists are
+// written as a count followed by their items.
+
+pub struct Writer {
+    out: String,
+}
+
+impl Writer {
+    pub fn new(magic: &str) -> Self {
+        let mut writer = Self { out: String::new() };
+        writer.word(magic);
+        writer.word(VERSION);
+        writer
+    }
+
+    pub fn word(&mut self, word: impl Display) {
+        if !self.out.is_empty() && !self.out.ends_with('\n') {
+            self.out.push(' ');
+        }
+        self.out.push_str(&word.to_string());
+    }
+
+    pub fn string(&mut self, s: &str) {
+        self.word(format!("\"{}\"", escape(s)));
+    }
+
+    pub fn line(&mut self) {
+        self.out.push('\n');
+    }
+
+    pub fn option(&mut self, value: Option<usize>) {
+        match value {
+            Some(n) => self.word(n),
+            None => self.word("-"),
+        }
+    }
+
+    pub fn list<T>(&mut self, items: &[T], mut item: impl FnMut(&mut Self, &T)) {
+        self.word(items.len());
+        for x in items {
+            item(self, x);
+        }
+    }
+
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::Nothing => self.word("void"),
+            Value::Int(n) => {
+                self.word("int");
+                self.word(n);
+            }
+            Value::BigInt(n) => {
+                self.word("big");
+                self.word(n);
+            }
+            Value::Bool(b) => {
+                self.word("bool");
+                self.word(b);
+            }
+            Value::String(s) => {
+                self.word("str");
+                self.string(s);
+            }
+            Value::Error(e) => {
+                self.word("error");
+                self.string(&e.kind);
+                self.string(&e.message);
+                self.string(&e.file);
+                self.word(e.line);
+            }
+            Value::Function(name) => {
+                self.word("fn");
+                self.string(name);
+            }
+            Value::Closure(handle) => {
+                self.word("closure");
+                self.word(handle);
+            }
+            Value::Coroutine(handle) => {
+                self.word("coroutine");
+                self.word(handle);
+            }
+            Value::Task(id) => {
+                self.word("task");
+                self.word(id);
+            }
+            Value::Channel(handle) => {
+                self.word("channel");
+                self.word(handle);
+            }
+        }
+    }
+
+    pub fn values(&mut self, values: &[Value]) {
+        self.list(values, |out, value| out.value(value));
+    }
+
+    pub fn finish(mut self) -> String {
+        self.line();
+        self.out
+    }
+}
+
+pub struct Reader {
+    tokens: VecDeque<TokenKind>,
+}
+
+pub fn invalid(what: impl Display) -> EngineError {
+    EngineError::Snapshot(format!("invalid saved state: {}", what))
+}
+
+impl Reader {
+    pub fn new(input: &str, magic: &str) -> Result<Self, EngineError> {
+        let mut tokens = VecDeque::new();
+        for (number, line) in input.lines().enumerate() {
+            let line = Tokenizer::new(line)
+                .tokenize()
+                .map_err(|e| invalid(format!("line {}: {}", number + 1, e.error)))?;
+            tokens.extend(line.into_iter().map(|token| token.kind));
+        }
+
+        let mut reader = Self { tokens };
+        reader.expect(magic)?;
+        let version: u32 = reader.number()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported version {}", version)));
+        }
+        Ok(reader)
+    }
+
+    pub fn word(&mut self) -> Result<String, EngineError> {
+        match self.tokens.pop_front() {
+            Some(TokenKind::Word(word)) => Ok(word),
+            Some(TokenKind::Str(s)) => Err(invalid(format!("unexpected string {:?}", s))),
+            None => Err(invalid("unexpected end")),
+        }
+    }
+
+    pub fn expect(&mut self, expected: &str) -> Result<(), EngineError> {
+        match self.word()? {
+            word if word == expected => Ok(()),
+            word => Err(invalid(format!(
+                "expected `{}`, found `{}`",
+                expected, word
+            ))),
+        }
+    }
+
+    pub fn number<T: FromStr>(&mut self) -> Result<T, EngineError> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| invalid(format!("expected a number, found `{}`", word)))
+    }
+
+    pub fn string(&mut self) -> Result<String, EngineError> {
+        match self.tokens.pop_front() {
+            Some(TokenKind::Str(s)) => Ok(s),
+            Some(TokenKind::Word(word)) => {
+                Err(invalid(format!("expected a string, found `{}`", word)))
+            }
+            None => Err(invalid("unexpected end")),
+        }
+    }
+
+    pub fn option(&mut self) -> Result<Option<usize>, EngineError> {
+        match self.word()?.as_str() {
+            "-" => Ok(None),
+            word => word
+                .parse()
+                .map(Some)
+                .map_err(|_| invalid(format!("expected a number, found `{}`", word))),
+        }
+    }
+
+    pub fn list<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, EngineError>,
+    ) -> Result<Vec<T>, EngineError> {
+        let count: usize = self.number()?;
+        (0..count).map(|_| item(self)).collect()
+    }
+
+    pub fn value(&mut self) -> Result<Value, EngineError> {
+        let value = match self.word()?.as_str() {
+            "void" => Value::Nothing,
+            "int" => Value::Int(self.number()?),
+            "big" => {
+                let digits = self.word()?;
+                let n = BigInt::parse(&digits).ok_or_else(|| invalid(&digits))?;
+                Value::from(n)
+            }
+            "bool" => Value::Bool(self.number()?),
+            "str" => Value::String(self.string()?),
+            "error" => Value::Error(Box::new(ErrorValue {
+                kind: self.string()?,
+                message: self.string()?,
+                file: self.string()?,
+                line: self.number()?,
+            })),
+            "fn" => Value::Function(self.string()?),
+            "closure" => Value::Closure(self.number()?),
+            "coroutine" => Value::Coroutine(self.number()?),
+            "task" => Value::Task(self.number()?),
+            "channel" => Value::Channel(self.number()?),
+            word => return Err(invalid(format!("unknown value `{}`", word))),
+        };
+        Ok(value)
+    }
+
+    pub fn values(&mut self) -> Result<Vec<Value>, EngineError> {
+        self.list(|input| input.value())
+    }
+
+    pub fn finish(self) -> Result<(), EngineError> {
+        match self.tokens.is_empty() {
+            true => Ok(()),
+            false => Err(invalid("trailing data")),
+        }
+    }
+}
+
+#[test]
+fn test_values_roundtrip() -> Result<(), EngineError> {
+    let values = vec![
+        Value::Nothing,
+        Value::Int(-3),
+        Value::from(BigInt::parse("-123456789012345678901234567890").unwrap()),
+        Value::Bool(false),
+        Value::String("a \"quoted\"; # line\n".into()),
+        Value::Function("f".into()),
+        Value::Closure(4),
+    ];
+
+    let mut out = Writer::new("test");
+    out.values(&values);
+    let mut input = Reader::new(&out.finish(), "test")?;
+
+    assert_eq!(input.values()?, values);
+    input.finish()?;
+    assert!(matches!(
+        Reader::new(&Writer::new("test").finish(), "other"),
+        Err(EngineError::Snapshot(_))
+    ));
+    Ok(())
+}
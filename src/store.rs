@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::command::{EngineError, Value};
+use crate::heap::handle_of;
+use crate::snapshot::{Reader, Writer};
+
+const STORE: &str = "onehour-store";
+
+// Tells apart the temporary files of commits made by one process.
+static COMMITS: AtomicUsize = AtomicUsize::new(0);
+
+/// A file holding the values of variables declared with `.persist`, shared
+/// by every program that uses the same file.
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+}
+
+impl Store {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn error(&self, message: impl std::fmt::Display) -> EngineError {
+        EngineError::Store(format!("{}: {}", self.path.display(), message))
+    }
+
+    pub fn load(&self) -> Result<HashMap<String, Value>, EngineError> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(self.error(e)),
+        };
+
+        let mut input = Reader::new(&contents, STORE).map_err(|e| self.error(e))?;
+        let entries = input
+            .list(|input| Ok((input.string()?, input.value()?)))
+            .map_err(|e| self.error(e))?;
+        input.finish().map_err(|e| self.error(e))?;
+
+        Ok(entries.into_iter().collect())
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+
+    /// Writes `updates` over the stored values. Concurrent commits take turns
+    /// on `<path>.lock`, and each merges into what the previous one left, so
+    /// no update is lost. The file is replaced in one rename, so readers see
+    /// either the old or the new contents.
+    pub fn commit(&self, updates: Vec<(String, Value)>) -> Result<(), EngineError> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.sibling(".lock"))
+            .map_err(|e| self.error(e))?;
+        // Held until this function returns.
+        lock.lock().map_err(|e| self.error(e))?;
+
+        let mut entries = self.load()?;
+        for (name, value) in updates {
+            if handle_of(&value).is_some() || matches!(value, Value::Task(_)) {
+                return Err(self.error(format!(
+                    "cannot persist `{}`: {} only exists while the program runs",
+                    name, value
+                )));
+            }
+            entries.insert(name, value);
+        }
+
+        let mut entries: Vec<_> = entries.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = Writer::new(STORE);
+        out.line();
+        out.list(&entries, |out, (name, value)| {
+            out.string(name);
+            out.value(value);
+            out.line();
+        });
+
+        let partial = self.sibling(&format!(
+            ".{}-{}.partial",
+            std::process::id(),
+            COMMITS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let result = std::fs::write(&partial, out.finish())
+            .and_then(|_| std::fs::rename(&partial, &self.path));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&partial);
+        }
+        result.map_err(|e| self.error(e))
+    }
+}
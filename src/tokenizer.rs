@@ -0,0 +1,255 @@
+use crate::command::EngineError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Word(String),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub column: usize,
+    pub width: usize,
+}
+
+#[derive(Debug)]
+pub struct LexError {
+    pub error: EngineError,
+    pub column: usize,
+}
+
+impl From<LexError> for EngineError {
+    fn from(e: LexError) -> Self {
+        e.error
+    }
+}
+
+impl Token {
+    pub fn word(&self) -> Option<&str> {
+        match &self.kind {
+            TokenKind::Word(w) => Some(w),
+            TokenKind::Str(_) => None,
+        }
+    }
+}
+
+pub struct Tokenizer<'a> {
+    line: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Self {
+            line,
+            chars: line.char_indices().peekable(),
+        }
+    }
+
+    pub fn tokenize(mut self) -> Result<Vec<Token>, LexError> {
+        let mut tokens = vec![];
+
+        while let Some(&(pos, ch)) = self.chars.peek() {
+            if ch.is_whitespace() {
+                self.chars.next();
+            } else if is_comment_start(ch) {
+                break;
+            } else if ch == '"' {
+                self.chars.next();
+                let value = self.read_string().map_err(|error| LexError {
+                    error,
+                    column: self.column(pos),
+                })?;
+                tokens.push(Token {
+                    kind: TokenKind::Str(value),
+                    column: self.column(pos),
+                    width: self.width(pos),
+                });
+            } else {
+                let word = self.read_word();
+                tokens.push(Token {
+                    kind: TokenKind::Word(word),
+                    column: self.column(pos),
+                    width: self.width(pos),
+                });
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    fn column(&self, pos: usize) -> usize {
+        self.line[..pos].chars().count() + 1
+    }
+
+    fn width(&mut self, start: usize) -> usize {
+        let end = self.chars.peek().map_or(self.line.len(), |&(pos, _)| pos);
+        self.line[start..end].chars().count()
+    }
+
+    fn read_word(&mut self) -> String {
+        let mut word = String::new();
+
+        while let Some(&(_, ch)) = self.chars.peek() {
+            if ch.is_whitespace() || ch == '"' || is_comment_start(ch) {
+                break;
+            }
+            word.push(ch);
+            self.chars.next();
+        }
+
+        word
+    }
+
+    fn read_string(&mut self) -> Result<String, EngineError> {
+        let mut value = String::new();
+
+        loop {
+            match self.chars.next() {
+                Some((_, '"')) => return Ok(value),
+                Some((_, '\\')) => value.push(self.read_escape()?),
+                Some((_, ch)) => value.push(ch),
+                None => return Err(EngineError::UnterminatedString),
+            }
+        }
+    }
+
+    fn read_escape(&mut self) -> Result<char, EngineError> {
+        match self.chars.next() {
+            Some((_, 'n')) => Ok('\n'),
+            Some((_, 't')) => Ok('\t'),
+            Some((_, '"')) => Ok('"'),
+            Some((_, '\\')) => Ok('\\'),
+            Some((_, 'u')) => self.read_unicode_escape(),
+            Some((_, ch)) => Err(EngineError::InvalidEscape(format!("\\{}", ch))),
+            None => Err(EngineError::UnterminatedString),
+        }
+    }
+
+    fn read_unicode_escape(&mut self) -> Result<char, EngineError> {
+        if !matches!(self.chars.next(), Some((_, '{'))) {
+            return Err(EngineError::InvalidEscape("\\u".into()));
+        }
+
+        let mut digits = String::new();
+        loop {
+            match self.chars.next() {
+                Some((_, '}')) => break,
+                Some((_, ch)) if ch.is_ascii_hexdigit() && digits.len() < 6 => digits.push(ch),
+                Some(_) => return Err(EngineError::InvalidEscape(format!("\\u{{{}", digits))),
+                None => return Err(EngineError::UnterminatedString),
+            }
+        }
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| EngineError::InvalidEscape(format!("\\u{{{}}}", digits)))
+    }
+}
+
+fn is_comment_start(ch: char) -> bool {
+    ch == ';' || ch == '#'
+}
+
+pub fn escape(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+
+    for ch in input.chars() {
+        match ch {
+            '\n' => output.push_str("\\n"),
+            '\t' => output.push_str("\\t"),
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            ch if ch.is_control() => output.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => output.push(ch),
+        }
+    }
+
+    output
+}
+
+#[test]
+fn test_words() -> Result<(), LexError> {
+    let tokens = Tokenizer::new("  push 10").tokenize()?;
+
+    assert_eq!(
+        tokens,
+        vec![
+            Token {
+                kind: TokenKind::Word("push".into()),
+                column: 3,
+                width: 4,
+            },
+            Token {
+                kind: TokenKind::Word("10".into()),
+                column: 8,
+                width: 2,
+            },
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_string_with_spaces_and_escapes() -> Result<(), LexError> {
+    let tokens = Tokenizer::new(r#"push "hello world\n\t\"q\" \\ \u{1F600}""#).tokenize()?;
+
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1].width, 35);
+    assert_eq!(
+        tokens[1].kind,
+        TokenKind::Str("hello world\n\t\"q\" \\ \u{1F600}".into())
+    );
+    Ok(())
+}
+
+#[test]
+fn test_bad_strings() {
+    assert!(matches!(
+        Tokenizer::new(r#"push "oops"#).tokenize(),
+        Err(LexError {
+            error: EngineError::UnterminatedString,
+            column: 6
+        })
+    ));
+    assert!(matches!(
+        Tokenizer::new(r#"push "\q""#).tokenize(),
+        Err(LexError {
+            error: EngineError::InvalidEscape(_),
+            ..
+        })
+    ));
+    assert!(matches!(
+        Tokenizer::new(r#"push "\u{110000}""#).tokenize(),
+        Err(LexError {
+            error: EngineError::InvalidEscape(_),
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_comments() -> Result<(), LexError> {
+    assert!(Tokenizer::new("; a comment").tokenize()?.is_empty());
+    assert!(Tokenizer::new("   # another one").tokenize()?.is_empty());
+
+    let tokens = Tokenizer::new(r#"push "a ; b # c" ; trailing"#).tokenize()?;
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(tokens[1].kind, TokenKind::Str("a ; b # c".into()));
+
+    let tokens = Tokenizer::new("add# glued").tokenize()?;
+    assert_eq!(tokens[0].kind, TokenKind::Word("add".into()));
+    Ok(())
+}
+
+#[test]
+fn test_escape_roundtrip() -> Result<(), LexError> {
+    let original = "a \"quoted\"\tline\\\n\u{7}";
+    let source = format!("\"{}\"", escape(original));
+    let tokens = Tokenizer::new(&source).tokenize()?;
+
+    assert_eq!(tokens[0].kind, TokenKind::Str(original.into()));
+    Ok(())
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use crate::command::Value;
+
+pub type WatchFn = dyn FnMut(&Hit) -> Action + Send;
+
+/// A condition checked after every instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    /// The variable is written with a value different from the one it had.
+    Change(String),
+    /// The variable is written with a value of the given type, e.g. `string`.
+    Type(String, String),
+    /// The operand stack grows to this many values, or shrinks below it.
+    Depth(usize),
+}
+
+impl Watch {
+    fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (Watch::Change(watched), Event::Write { name, old, new }) => {
+                watched == name && old.as_ref() != Some(new)
+            }
+            (Watch::Type(watched, type_name), Event::Write { name, new, .. }) => {
+                watched == name && new.type_name() == type_name
+            }
+            (Watch::Depth(depth), Event::Depth { from, to }) => (from < depth) != (to < depth),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Change(name) => write!(f, "`{}` changes", name),
+            Watch::Type(name, type_name) => write!(f, "`{}` is set to a {}", name, type_name),
+            Watch::Depth(depth) => write!(f, "stack depth crosses {}", depth),
+        }
+    }
+}
+
+/// Something an instruction did that a watchpoint may be interested in.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Write {
+        name: String,
+        old: Option<Value>,
+        new: Value,
+    },
+    Depth {
+        from: usize,
+        to: usize,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Write {
+                name,
+                old: Some(old),
+                new,
+            } => write!(f, "`{}` changed from {} to {}", name, old, new),
+            Event::Write {
+                name,
+                old: None,
+                new,
+            } => write!(f, "`{}` set to {}", name, new),
+            Event::Depth { from, to } => write!(f, "stack depth went from {} to {}", from, to),
+        }
+    }
+}
+
+/// A watchpoint that fired, and the instruction that made it fire.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub id: usize,
+    pub pc: usize,
+    pub watch: Watch,
+    pub event: Event,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watchpoint {} ({}): {}", self.id, self.watch, self.event)
+    }
+}
+
+/// What evaluation does after a watchpoint callback returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Continue,
+    /// Stops with `EngineError::Paused`; `Evaluator::resume` carries on.
+    Pause,
+}
+
+pub struct Watchpoint {
+    pub id: usize,
+    pub watch: Watch,
+    pub callback: Box<WatchFn>,
+}
+
+impl Watchpoint {
+    /// Calls the callback for every event the watchpoint matches, and
+    /// returns whether any of them asked to pause.
+    pub fn notify(&mut self, pc: usize, events: &[Event]) -> bool {
+        let mut pause = false;
+        for event in events.iter().filter(|event| self.watch.matches(event)) {
+            let hit = Hit {
+                id: self.id,
+                pc,
+                watch: self.watch.clone(),
+                event: event.clone(),
+            };
+            pause |= (self.callback)(&hit) == Action::Pause;
+        }
+        pause
+    }
+}
+
+#[test]
+fn test_watch_matches() {
+    let write = |old: Option<i64>, new: Value| Event::Write {
+        name: "x".into(),
+        old: old.map(Value::Int),
+        new,
+    };
+
+    let change = Watch::Change("x".into());
+    assert!(change.matches(&write(None, Value::Int(1))));
+    assert!(change.matches(&write(Some(1), Value::Int(2))));
+    assert!(!change.matches(&write(Some(1), Value::Int(1))));
+    assert!(!Watch::Change("y".into()).matches(&write(None, Value::Int(1))));
+
+    let string = Watch::Type("x".into(), "string".into());
+    assert!(string.matches(&write(Some(1), Value::String("1".into()))));
+    assert!(!string.matches(&write(Some(1), Value::Int(1))));
+
+    let depth = Watch::Depth(3);
+    assert!(depth.matches(&Event::Depth { from: 2, to: 3 }));
+    assert!(depth.matches(&Event::Depth { from: 3, to: 2 }));
+    assert!(!depth.matches(&Event::Depth { from: 3, to: 4 }));
+    assert!(!depth.matches(&Event::Depth { from: 0, to: 2 }));
+}